@@ -1,7 +1,8 @@
-use nannou::{color::{BLACK, WHITE}, event::Update, glam::Vec2, rand::random_range, App, Frame};
-mod quadtree;
-use quadtree::{Point, QuadTree};
-use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use nannou::{color::{hsv, rgba, Srgb, BLACK, GRAY, WHITE}, event::{MouseButton, Update}, geom::Rect, glam::Vec2, App, Frame};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use quad_tree::quadtree::{IndexQuadTree, Point, QuadTree, DEFAULT_RESTITUTION};
 
 fn main() {
     rayon::ThreadPoolBuilder::new().num_threads(8).build_global().unwrap();
@@ -11,22 +12,401 @@ fn main() {
         .run();
 }
 
+/// Real time covered by one physics step.
+const FIXED_STEP: f32 = 1.0 / 90.0;
+
+/// Default strength of the gravity, which points straight down.
+const GRAVITY: f32 = 2200.0;
+
+/// Physics substeps per step.
+const SUBSTEPS: usize = 4;
+
+/// Most substeps per step chosen by the automatic substep count.
+const MAX_SUBSTEPS: usize = 32;
+
+/// Approach speed in world units per second below which colliding points don't bounce, so
+/// resting piles settle instead of jittering.
+const RESTITUTION_THRESHOLD: f32 = 60.0;
+
+/// Vertical speed in world units per second below which a point on the bottom wall rests.
+const RESTING_SPEED: f32 = 20.0;
+
+/// Frames a point has to rest on the bottom wall before it counts as landed.
+const LANDING_FRAMES: u32 = 3;
+
+/// Draw cap preset when the cap is switched on.
+const DEFAULT_MAX_DRAWN: usize = 10_000;
+
+/// Most physics steps run in one frame with the fixed timestep. Time beyond that is dropped, so a
+/// stall doesn't make every following frame slower while catching up.
+const MAX_STEPS_PER_FRAME: usize = 4;
+
+/// Most lines drawn by the mesh view per frame. Pairs grow quadratically with the density, beyond
+/// this the mesh is cut off instead of stalling the frame.
+const MAX_MESH_LINES: usize = 20_000;
+
+/// Neighbor count at which the density coloring reaches its hottest color.
+const DENSITY_SATURATION: f32 = 12.0;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum SpawnerMode {
     Inactive,
     TopLeft,
 }
 
+/// What a left click into the scene does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClickMode {
+    Nothing,
+    PlaceObstacle,
+    SpawnPoint,
+    /// Pins or unpins the point closest to the mouse.
+    TogglePin,
+}
+
+/// Aggregates over the simulated frames since the last reset.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SessionStats {
+    frames: u64,
+    max_points: usize,
+    max_depth: usize,
+    total_points: u64,
+    total_collisions_resolved: u64,
+}
+
+impl SessionStats {
+    fn record(&mut self, points: usize, depth: usize, collisions_resolved: usize) {
+        self.frames += 1;
+        self.max_points = self.max_points.max(points);
+        self.max_depth = self.max_depth.max(depth);
+        self.total_points += points as u64;
+        self.total_collisions_resolved += collisions_resolved as u64;
+    }
+
+    fn average_points(&self) -> f64 {
+        self.total_points as f64 / self.frames.max(1) as f64
+    }
+
+    fn average_collisions_resolved(&self) -> f64 {
+        self.total_collisions_resolved as f64 / self.frames.max(1) as f64
+    }
+}
+
+const TRAIL_CAPACITY: usize = 8;
+
+/// Ring buffer of the most recent positions of a point.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Trail {
+    positions: [Vec2; TRAIL_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl Trail {
+    fn push(&mut self, position: Vec2) {
+        self.head = (self.head + 1) % TRAIL_CAPACITY;
+        self.positions[self.head] = position;
+        self.len = (self.len + 1).min(TRAIL_CAPACITY);
+    }
+
+    /// Iterates the recorded positions from newest to oldest.
+    fn iter(&self) -> impl Iterator<Item = Vec2> + '_ {
+        (0..self.len).map(move |i| self.positions[(self.head + TRAIL_CAPACITY - i) % TRAIL_CAPACITY])
+    }
+}
+
+/// Boundary the points are kept in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Container {
+    /// The window, open at the top.
+    Rect,
+    Circle { center: Vec2, radius: f32 },
+}
+
+/// Camera mapping world coordinates, which the simulation and tree run in, to screen coordinates,
+/// which nannou reports the mouse in. Positions go through `screen_to_world` before any query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct View {
+    /// World position shown at the center of the window.
+    offset: Vec2,
+    /// Screen units per world unit.
+    scale: f32,
+}
+
+impl View {
+    const IDENTITY: View = View { offset: Vec2::ZERO, scale: 1.0 };
+
+    fn screen_to_world(&self, screen: Vec2) -> Vec2 {
+        screen / self.scale + self.offset
+    }
+
+    fn world_to_screen(&self, world: Vec2) -> Vec2 {
+        (world - self.offset) * self.scale
+    }
+
+    /// Applies the camera to `draw`, so everything drawn with the result is given in world coordinates.
+    fn transform(&self, draw: &nannou::draw::Draw) -> nannou::draw::Draw {
+        draw.translate(self.world_to_screen(Vec2::ZERO).extend(0.0)).scale(self.scale)
+    }
+}
+
+/// The inputs of one frame, which together with the seed reproduce a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameInput {
+    since_last: f32,
+    mouse: Vec2,
+    clicked: bool,
+}
+
+/// Writes a recording as a `seed` line followed by one `since_last mouse_x mouse_y clicked` line per frame.
+fn write_recording(path: &Path, seed: u64, frames: &[FrameInput]) -> io::Result<()> {
+    let mut text = format!("seed {}\n", seed);
+    for frame in frames {
+        text += &format!("{} {} {} {}\n", frame.since_last, frame.mouse.x, frame.mouse.y, frame.clicked as u8);
+    }
+    fs::write(path, text)
+}
+
+fn read_recording(path: &Path) -> io::Result<(u64, Vec<FrameInput>)> {
+    let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid recording line: {:?}", line));
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or_default();
+    let seed = header.strip_prefix("seed ").and_then(|seed| seed.parse().ok()).ok_or_else(|| invalid(header))?;
+    let frames = lines.map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [since_last, x, y, clicked] = fields[..] else { return Err(invalid(line)) };
+        let parse = |field: &str| field.parse::<f32>().map_err(|_| invalid(line));
+        Ok(FrameInput {
+            since_last: parse(since_last)?,
+            mouse: Vec2::new(parse(x)?, parse(y)?),
+            clicked: clicked == "1",
+        })
+    }).collect::<io::Result<_>>()?;
+    Ok((seed, frames))
+}
+
+/// Appends one `generation,id,x,y` line per point to the CSV file at `path`, creating it if needed.
+fn append_positions_csv(path: &Path, generation: u64, points: &[Point]) -> io::Result<()> {
+    let mut text = String::new();
+    for point in points {
+        text += &format!("{},{},{},{}\n", generation, point.id, point.position.x, point.position.y);
+    }
+    fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(text.as_bytes())
+}
+
+/// Bounds of the tree over the window `rect` as `(x, y, width, height)`, anchored at its bottom
+/// left corner so a window that isn't centered on the origin is covered too.
+fn tree_bounds(rect: Rect) -> (f32, f32, f32, f32) {
+    (rect.left(), rect.bottom(), rect.w(), rect.h())
+}
+
+/// Formats `point` as an `id,x,y,prev_x,prev_y,radius,r,g,b` line of a points snapshot.
+fn point_csv_row(point: &Point) -> String {
+    format!("{},{},{},{},{},{},{},{},{}\n", point.id, point.position.x, point.position.y, point.prev_position.x, point.prev_position.y, point.radius, point.color.red, point.color.green, point.color.blue)
+}
+
+/// Writes a snapshot of `points` with a header line to a new CSV file named after the current
+/// time and returns its path.
+fn export_points_csv(points: &[Point]) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+    let path = PathBuf::from(format!("points_{}.csv", timestamp));
+    let mut text = String::from("id,x,y,prev_x,prev_y,radius,r,g,b\n");
+    for point in points {
+        text += &point_csv_row(point);
+    }
+    fs::write(&path, text)?;
+    Ok(path)
+}
+
+/// Fraction of the way from `start` to `end` at which a circle first touches one of radius
+/// `radius` around `center`, measured as the distance between the centers. `None` when the path
+/// misses or the circles already overlap at `start`.
+fn time_of_impact(start: Vec2, end: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let direction = end - start;
+    let offset = start - center;
+    let a = direction.length_squared();
+    let b = 2.0 * offset.dot(direction);
+    let c = offset.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if c <= 0.0 || a == 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    (0.0..=1.0).contains(&t).then_some(t)
+}
+
+/// Startup tunables of the simulation. `Model::new` launches with `ModelConfig::default()`.
+#[derive(Debug, Clone, PartialEq)]
+struct ModelConfig {
+    points_per_quad: usize,
+    show_quad_tree: bool,
+    spawner_mode: SpawnerMode,
+    mouse_radius: f32,
+    minimum_size: f32,
+    maximum_size: f32,
+    solver_iterations: usize,
+    spawn_rate: f32,
+    rest_threshold: f32,
+    /// Fraction of the overlap between two points resolved per solver iteration.
+    stiffness: f32,
+    /// Side length of a square grid of points placed in the window on start, 0 for none.
+    initial_grid: usize,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            points_per_quad: 16,
+            show_quad_tree: true,
+            spawner_mode: SpawnerMode::TopLeft,
+            mouse_radius: 16.0,
+            minimum_size: 8.0,
+            maximum_size: 16.0,
+            solver_iterations: 2,
+            spawn_rate: 60.0,
+            rest_threshold: 0.0,
+            stiffness: 1.0,
+            initial_grid: 0,
+        }
+    }
+}
+
+impl ModelConfig {
+    fn sparse() -> Self {
+        ModelConfig {
+            points_per_quad: 4,
+            spawner_mode: SpawnerMode::Inactive,
+            minimum_size: 12.0,
+            maximum_size: 24.0,
+            initial_grid: 8,
+            ..Default::default()
+        }
+    }
+
+    fn dense() -> Self {
+        ModelConfig {
+            points_per_quad: 32,
+            minimum_size: 5.0,
+            maximum_size: 8.0,
+            solver_iterations: 4,
+            spawn_rate: 240.0,
+            initial_grid: 40,
+            ..Default::default()
+        }
+    }
+}
+
 struct Model {
     points: Vec<Point>,
     points_per_quad: usize,
     running: bool,
     show_quad_tree: bool,
-    egui: nannou_egui::Egui,
+    graded_outlines: bool,
+    skip_empty_quads: bool,
+    show_trails: bool,
+    trail_length: usize,
+    /// Recent positions of each point, indexed like `points`. Only recorded while `show_trails` is on.
+    trails: Vec<Trail>,
+    density_coloring: bool,
+    density_radius: f32,
+    cluster_coloring: bool,
+    show_mesh: bool,
+    show_centroid: bool,
+    mesh_radius: f32,
+    /// Draw only every nth point so at most this many are drawn. All points are still simulated.
+    max_drawn: Option<usize>,
+    /// `None` without a window, as in tests.
+    egui: Option<nannou_egui::Egui>,
     spawner_mode: SpawnerMode,
     mouse_radius: f32,
     minimum_size: f32,
     maximum_size: f32,
+    solver_iterations: usize,
+    substeps: usize,
+    /// Raise the substeps above `substeps` while the fastest point would move further than the
+    /// smallest radius in one substep.
+    auto_substeps: bool,
+    /// Substeps of the current step, which the Verlet velocities are relative to.
+    active_substeps: usize,
+    spawn_rate: f32,
+    spawn_accumulator: f32,
+    /// Largest offset along each axis applied to a point spawned exactly on top of another one.
+    anti_coincidence_jitter: f32,
+    elliptical_points: bool,
+    /// Color spawned points with `palette` in turn instead of randomly.
+    use_palette: bool,
+    palette: Vec<Srgb>,
+    /// Index of the next palette color, wrapping around.
+    palette_index: usize,
+    /// Spawned points get a restitution drawn from this range.
+    minimum_restitution: f32,
+    maximum_restitution: f32,
+    /// Advanced once per frame. Spawned points and every tree built in the frame carry it, so
+    /// `QuadTree::query_radius_newer_than` tells the points of this frame from older ones.
+    generation: u64,
+    rest_threshold: f32,
+    stiffness: f32,
+    /// Take collision candidates from touching leaves instead of radius queries. Only finds every
+    /// overlap while no leaf is smaller than `maximum_size * 2`.
+    leaf_based_collisions: bool,
+    /// Resolve collisions from the bottom up instead of all at once.
+    bottom_up_collisions: bool,
+    /// Search collision pairs on all threads. Only changes the speed, never the result.
+    parallel: bool,
+    /// Sweep points moving further than their radius in a substep, so small fast points cannot
+    /// tunnel through thin obstacles or other points.
+    continuous_collisions: bool,
+    collision_checks: usize,
+    /// Overlapping pairs pushed apart in the last frame, once per solver iteration they overlap in.
+    collisions_resolved: usize,
+    /// Depth of the last tree built for the collision resolution.
+    tree_depth: usize,
+    /// Points that came to rest on the bottom wall, counted once per landing.
+    landings: usize,
+    session_stats: SessionStats,
+    show_histogram: bool,
+    show_query_trace: bool,
+    time_scale: f32,
+    /// Kept apart from the angle, so the angle survives dragging the magnitude to 0.
+    gravity_magnitude: f32,
+    /// Direction of the gravity in degrees, counterclockwise from straight down.
+    gravity_angle: f32,
+    /// Run as many physics steps per frame as fit into the elapsed time instead of exactly one.
+    fixed_timestep: bool,
+    physics_accumulator: f32,
+    /// Real time covered by the physics steps run since the last restart, `FIXED_STEP` per step.
+    simulated_time: f64,
+    click_mode: ClickMode,
+    /// Points spawned by clicking start with the velocity of the mouse.
+    inherit_mouse_velocity: bool,
+    /// Mouse position of the previous frame.
+    prev_mouse: Vec2,
+    /// Immovable circles as center and radius.
+    obstacles: Vec<(Vec2, f32)>,
+    obstacle_radius: f32,
+    container: Container,
+    mouse_radius_in_pixels: bool,
+    /// Push points away from the mouse through the collision resolution, as a pinned point of
+    /// `mouse_radius` in the tree, instead of with a separate pass over all points.
+    mouse_in_tree: bool,
+    /// Seed of `rng`, the only source of randomness in the simulation.
+    seed: u64,
+    rng: StdRng,
+    /// Set by a left click into the scene and consumed by the next `update`.
+    click_pending: bool,
+    recording_path: String,
+    /// Append the point positions of every frame to `positions.csv`.
+    log_positions: bool,
+    recording: Option<(PathBuf, Vec<FrameInput>)>,
+    /// Outcome of the last "Export CSV", shown below the button.
+    export_status: Option<String>,
+    replay: Option<std::vec::IntoIter<FrameInput>>,
+    camera: View,
+    /// Tree drawn by `view` while paused, see `refresh_tree_cache`.
+    cached_tree: Option<QuadTree>,
+    /// Set when points are added or removed, or the tree layout changes, while paused.
+    tree_dirty: bool,
 }
 
 impl Model {
@@ -35,199 +415,1744 @@ impl Model {
             .size(800, 800)
             .view(Model::view)
             .raw_event(Model::raw_window_event)
+            .mouse_pressed(Model::mouse_pressed)
             .build()
             .unwrap();
 
         let window = app.window(window_id).unwrap();
 
-        Model {
+        let mut model = Model::from_config(&ModelConfig::default(), rand::random(), window.rect());
+        model.egui = Some(nannou_egui::Egui::from_window(&window));
+        model
+    }
+
+    /// Builds a model without a window from `config`, with the RNG seeded by `seed` and the
+    /// initial points placed in `rect`.
+    fn from_config(config: &ModelConfig, seed: u64, rect: Rect) -> Model {
+        let mut model = Model {
             points: Vec::new(),
-            points_per_quad: 16,
+            points_per_quad: config.points_per_quad,
             running: true,
-            show_quad_tree: true,
-            egui: nannou_egui::Egui::from_window(&window),
-            spawner_mode: SpawnerMode::TopLeft,
-            mouse_radius: 16.0,
-            minimum_size: 8.0,
-            maximum_size: 16.0,
+            show_quad_tree: config.show_quad_tree,
+            graded_outlines: false,
+            skip_empty_quads: false,
+            show_trails: false,
+            trail_length: TRAIL_CAPACITY,
+            trails: Vec::new(),
+            density_coloring: false,
+            density_radius: 40.0,
+            cluster_coloring: false,
+            show_mesh: false,
+            show_centroid: false,
+            mesh_radius: 40.0,
+            max_drawn: None,
+            egui: None,
+            spawner_mode: config.spawner_mode,
+            mouse_radius: config.mouse_radius,
+            minimum_size: config.minimum_size,
+            maximum_size: config.maximum_size,
+            solver_iterations: config.solver_iterations,
+            substeps: SUBSTEPS,
+            auto_substeps: false,
+            active_substeps: SUBSTEPS,
+            spawn_rate: config.spawn_rate,
+            spawn_accumulator: 0.0,
+            anti_coincidence_jitter: 0.0,
+            elliptical_points: false,
+            use_palette: false,
+            palette: vec![
+                Srgb::new(0.94, 0.33, 0.31),
+                Srgb::new(0.98, 0.75, 0.29),
+                Srgb::new(0.40, 0.78, 0.45),
+                Srgb::new(0.26, 0.62, 0.89),
+                Srgb::new(0.62, 0.43, 0.86),
+            ],
+            palette_index: 0,
+            minimum_restitution: DEFAULT_RESTITUTION,
+            maximum_restitution: DEFAULT_RESTITUTION,
+            generation: 0,
+            rest_threshold: config.rest_threshold,
+            stiffness: config.stiffness,
+            leaf_based_collisions: false,
+            bottom_up_collisions: false,
+            parallel: true,
+            continuous_collisions: false,
+            collision_checks: 0,
+            collisions_resolved: 0,
+            tree_depth: 0,
+            landings: 0,
+            session_stats: SessionStats::default(),
+            show_histogram: false,
+            show_query_trace: false,
+            time_scale: 1.0,
+            gravity_magnitude: GRAVITY,
+            gravity_angle: 0.0,
+            fixed_timestep: false,
+            physics_accumulator: 0.0,
+            simulated_time: 0.0,
+            click_mode: ClickMode::Nothing,
+            inherit_mouse_velocity: false,
+            prev_mouse: Vec2::ZERO,
+            obstacles: Vec::new(),
+            obstacle_radius: 24.0,
+            container: Container::Rect,
+            mouse_radius_in_pixels: false,
+            mouse_in_tree: false,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            click_pending: false,
+            recording_path: String::from("recording.txt"),
+            log_positions: false,
+            recording: None,
+            export_status: None,
+            replay: None,
+            cached_tree: None,
+            tree_dirty: false,
+            camera: View::IDENTITY,
+        };
+        model.spawn_grid(config.initial_grid, rect);
+        model
+    }
+
+    /// Replaces the tunables with `config` and restarts from its initial points.
+    fn apply_config(&mut self, config: &ModelConfig, rect: Rect) {
+        self.points_per_quad = config.points_per_quad;
+        self.show_quad_tree = config.show_quad_tree;
+        self.spawner_mode = config.spawner_mode;
+        self.mouse_radius = config.mouse_radius;
+        self.minimum_size = config.minimum_size;
+        self.maximum_size = config.maximum_size;
+        self.solver_iterations = config.solver_iterations;
+        self.spawn_rate = config.spawn_rate;
+        self.rest_threshold = config.rest_threshold;
+        self.stiffness = config.stiffness;
+        self.spawn_accumulator = 0.0;
+        self.points.clear();
+        self.trails.clear();
+        self.tree_dirty = true;
+        self.spawn_grid(config.initial_grid, rect);
+    }
+
+    /// Restarts from the default config with the RNG seeded by `seed`, the state recordings start
+    /// from. Only settings that don't change the simulation carry over, everything else is reset
+    /// to its default, so a replay doesn't depend on how the UI was left.
+    fn restart(&mut self, seed: u64, rect: Rect) {
+        let fresh = Model::from_config(&ModelConfig::default(), seed, rect);
+        *self = Model {
+            egui: self.egui.take(),
+            show_quad_tree: self.show_quad_tree,
+            graded_outlines: self.graded_outlines,
+            skip_empty_quads: self.skip_empty_quads,
+            show_trails: self.show_trails,
+            trail_length: self.trail_length,
+            density_coloring: self.density_coloring,
+            density_radius: self.density_radius,
+            cluster_coloring: self.cluster_coloring,
+            show_mesh: self.show_mesh,
+            show_centroid: self.show_centroid,
+            mesh_radius: self.mesh_radius,
+            max_drawn: self.max_drawn,
+            use_palette: self.use_palette,
+            palette: std::mem::take(&mut self.palette),
+            parallel: self.parallel,
+            session_stats: std::mem::take(&mut self.session_stats),
+            show_histogram: self.show_histogram,
+            show_query_trace: self.show_query_trace,
+            recording_path: std::mem::take(&mut self.recording_path),
+            log_positions: self.log_positions,
+            camera: self.camera,
+            ..fresh
+        };
+    }
+
+    /// Restarts with a fresh seed and records the inputs of every following frame until `stop_recording`.
+    fn start_recording(&mut self, path: impl Into<PathBuf>, rect: Rect) {
+        self.replay = None;
+        self.restart(rand::random(), rect);
+        self.recording = Some((path.into(), Vec::new()));
+    }
+
+    fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recording.take() {
+            Some((path, frames)) => write_recording(&path, self.seed, &frames),
+            None => Ok(()),
         }
     }
 
-    fn update(app: &App, model: &mut Model, update: Update) {
-        model.update_egui(update);
-        
-        match model.spawner_mode {
-            SpawnerMode::Inactive => (),
-            SpawnerMode::TopLeft => {
-                let pos1 = Vec2::new(app.window_rect().left() + model.maximum_size, app.window_rect().top() - model.maximum_size);
-                let pos2 = Vec2::new(app.window_rect().left() + model.maximum_size, app.window_rect().top() - model.maximum_size * 3.0);
-                let pos3 = Vec2::new(app.window_rect().left() + model.maximum_size, app.window_rect().top() - model.maximum_size * 5.0);
-                let pos4 = Vec2::new(app.window_rect().left() + model.maximum_size, app.window_rect().top() - model.maximum_size * 7.0);
-                let arr = [pos1, pos2, pos3, pos4];
-                arr.iter().for_each(|pos| {
-                    if model.points.iter().find(|p| p.position.distance(*pos) < model.maximum_size * 2.0).is_none() {
-                        model.spawn_point(*pos);
-                    }
-                });
+    /// Restarts with the seed of the recording at `path` and feeds its inputs to the following frames
+    /// instead of the live mouse. Settings changed through the UI during the run are not recorded.
+    fn replay(&mut self, path: impl AsRef<Path>, rect: Rect) -> io::Result<()> {
+        let (seed, frames) = read_recording(path.as_ref())?;
+        self.recording = None;
+        self.restart(seed, rect);
+        self.replay = Some(frames.into_iter());
+        Ok(())
+    }
+
+    /// Returns the inputs of this frame, taken from the replay while one is running instead of
+    /// `live` and recorded while recording.
+    fn next_input(&mut self, live: FrameInput) -> FrameInput {
+        let input = match self.replay.as_mut().map(|frames| frames.next()) {
+            Some(Some(input)) => input,
+            Some(None) => {
+                self.replay = None;
+                live
             },
+            None => live,
+        };
+        if let Some((_, frames)) = &mut self.recording {
+            frames.push(input);
         }
-        
-        if !model.running || model.points.is_empty() {
-            return;
+        input
+    }
+
+    fn spawn_grid(&mut self, side: usize, rect: Rect) {
+        // Grid positions are further apart than any point is wide, only the points already there can coincide.
+        let occupied = self.coincidence_tree(rect);
+        let spacing = self.maximum_size * 2.0 + 1.0;
+        let start = rect.xy() - Vec2::splat(spacing * (side as f32 - 1.0) / 2.0);
+        for row in 0..side {
+            for column in 0..side {
+                let position = start + Vec2::new(column as f32, row as f32) * spacing;
+                if rect.contains(position) {
+                    self.spawn_point(position, occupied.as_ref());
+                }
+            }
         }
+    }
+
+    fn update(app: &App, model: &mut Model, update: Update) {
+        model.generation += 1;
+        model.update_egui(update, app.window_rect());
+        let live = FrameInput {
+            since_last: update.since_last.as_secs_f32(),
+            mouse: model.camera.screen_to_world(app.mouse.position()),
+            clicked: std::mem::take(&mut model.click_pending),
+        };
+        let input = model.next_input(live);
+        model.advance(input, app.window_rect(), app.main_window().scale_factor());
+    }
+
+    /// Runs one frame with `input` in the window `rect`. `scale_factor` is the window's, used for a
+    /// mouse radius given in pixels.
+    fn advance(&mut self, input: FrameInput, rect: Rect, scale_factor: f32) {
+        if input.clicked {
+            match self.click_mode {
+                ClickMode::Nothing => (),
+                ClickMode::PlaceObstacle => self.obstacles.push((input.mouse, self.obstacle_radius)),
+                ClickMode::SpawnPoint => {
+                    let occupied = self.coincidence_tree(rect);
+                    self.spawn_point(input.mouse, occupied.as_ref());
+                    if self.inherit_mouse_velocity && input.since_last > 0.0 {
+                        // Verlet velocity is the displacement per substep.
+                        let mouse_velocity = (input.mouse - self.prev_mouse) / input.since_last;
+                        let delta = self.substep_delta();
+                        let point = self.points.last_mut().unwrap();
+                        point.set_velocity(mouse_velocity * delta);
+                    }
+                },
+                ClickMode::TogglePin => {
+                    let nearest = self.build_tree(rect).nearest(input.mouse.x, input.mouse.y).map(|p| p.id);
+                    if let Some(point) = self.points.iter_mut().find(|p| Some(p.id) == nearest) {
+                        point.pinned = !point.pinned;
+                        point.set_velocity(Vec2::ZERO);
+                    }
+                },
+            }
+        }
+        self.prev_mouse = input.mouse;
+        self.fit_container(rect);
         
-        let gravity = -2200.0;
-        
-        let substeps: i32 = 4;
-        for _ in 0..substeps {            
-            model.resolve_collisions(app);
+        let simulate = self.running && !self.points.is_empty();
+        if simulate {
+            let steps = if self.fixed_timestep {
+                self.physics_accumulator += input.since_last;
+                let mut steps = 0;
+                while self.physics_accumulator >= FIXED_STEP {
+                    self.physics_accumulator -= FIXED_STEP;
+                    steps += 1;
+                }
+                steps.min(MAX_STEPS_PER_FRAME)
+            } else {
+                1
+            };
+
+            let substeps = if self.auto_substeps { self.needed_substeps() } else { self.substeps };
+            self.set_substeps(substeps);
+
+            self.collision_checks = 0;
+            self.collisions_resolved = 0;
+            self.simulated_time += steps as f64 * FIXED_STEP as f64;
+            for _ in 0..steps {
+                self.step(rect, input.mouse, self.mouse_radius_world(scale_factor));
+            }
             
-            model.resolve_wall_collisions(gravity, app);
+            if self.show_trails {
+                self.trails.resize(self.points.len(), Trail::default());
+                for (trail, point) in self.trails.iter_mut().zip(&self.points) {
+                    trail.push(point.position);
+                }
+            } else {
+                self.trails.clear();
+            }
+            // Frames without a step leave the points where they were, so they neither advance
+            // nor reset the landing counters.
+            if steps > 0 {
+                self.landings += self.detect_landings().len();
+            }
+            if self.log_positions {
+                // Straight from the points, so points that left the window are logged too.
+                if let Err(err) = append_positions_csv(Path::new("positions.csv"), self.generation, &self.points) {
+                    eprintln!("Failed to log positions: {}", err);
+                    self.log_positions = false;
+                }
+            }
+            self.session_stats.record(self.points.len(), self.tree_depth, self.collisions_resolved);
+        }
+
+        let spawn_positions = self.spawn_positions(rect);
+        // Spawns are paid from a time based budget, capped so a blocked spawner doesn't burst once it frees up.
+        self.spawn_accumulator = (self.spawn_accumulator + self.spawn_rate * input.since_last).min(spawn_positions.len() as f32);
+        // The tree of the positions after the physics is only built while a spawn is due. The
+        // spawn positions are `maximum_size * 2` apart, so points spawned below never block each
+        // other and the tree doesn't need them.
+        if self.spawn_accumulator >= 1.0 {
+            let index_tree = self.build_index_tree(rect);
+            let spawn_distance = self.maximum_size * 2.0;
+            for pos in &spawn_positions {
+                if self.spawn_accumulator >= 1.0 && !index_tree.any_within(&self.points, pos.x, pos.y, spawn_distance, None) {
+                    self.spawn_point(*pos, Some(&index_tree));
+                    self.spawn_accumulator -= 1.0;
+                }
+            }
+        }
+        self.refresh_tree_cache(rect);
+    }
+
+    /// Advances the count of stepped frames each point rested on the bottom wall and returns the
+    /// ids of the points that just landed. A point lands once until it leaves the floor again.
+    fn detect_landings(&mut self) -> Vec<usize> {
+        let delta = self.substep_delta();
+        let mut landed = Vec::new();
+        for point in &mut self.points {
+            let resting = std::mem::take(&mut point.floor_contact) && (point.velocity().y / delta).abs() < RESTING_SPEED;
+            point.floor_frames = if resting { point.floor_frames.saturating_add(1) } else { 0 };
+            if point.floor_frames == LANDING_FRAMES {
+                landed.push(point.id);
+            }
+        }
+        landed
+    }
+
+    /// Positions the spawner places points at.
+    fn spawn_positions(&self, rect: Rect) -> Vec<Vec2> {
+        match self.spawner_mode {
+            SpawnerMode::Inactive => Vec::new(),
+            SpawnerMode::TopLeft => {
+                let pos1 = Vec2::new(rect.left() + self.maximum_size, rect.top() - self.maximum_size);
+                let pos2 = Vec2::new(rect.left() + self.maximum_size, rect.top() - self.maximum_size * 3.0);
+                let pos3 = Vec2::new(rect.left() + self.maximum_size, rect.top() - self.maximum_size * 5.0);
+                let pos4 = Vec2::new(rect.left() + self.maximum_size, rect.top() - self.maximum_size * 7.0);
+                vec![pos1, pos2, pos3, pos4]
+            },
+        }
+    }
+
+    /// Kinetic and potential energy of all points, taking the area of a point as its mass and the
+    /// bottom left corner of `rect` as zero potential. Velocities are recovered from the last Verlet substep.
+    fn energy(&self, rect: Rect) -> (f32, f32) {
+        let delta = self.substep_delta();
+        self.points.iter().fold((0.0, 0.0), |(kinetic, potential), point| {
+            let mass = std::f32::consts::PI * point.radius * point.radius;
+            let velocity = point.velocity() / delta;
+            (kinetic + 0.5 * mass * velocity.length_squared(), potential - mass * self.gravity().dot(point.position - rect.bottom_left()))
+        })
+    }
+
+    /// Advances the physics by `FIXED_STEP` scaled by the time scale.
+    fn step(&mut self, rect: Rect, mouse: Vec2, mouse_radius: f32) {
+        // Integrate first and project afterwards, so every substep ends with the constraints
+        // satisfied. Repeating the projection lets corrections travel up through a resting pile
+        // instead of the pile slowly compressing under gravity.
+        let delta = self.substep_delta();
+        for _ in 0..self.active_substeps {            
+            self.resolve_wall_collisions(rect);
             
-            model.resolve_mouse_collisions(app);
+            self.integrate(delta);
+
+            if self.continuous_collisions {
+                self.resolve_swept_collisions(rect);
+            }
             
-            model.resolve_collisions(app);
+            let mouse_point = if self.mouse_in_tree {
+                self.points.push(Model::mouse_point(mouse, mouse_radius));
+                Some(self.points.len() - 1)
+            } else {
+                self.resolve_mouse_collisions(mouse, mouse_radius);
+                None
+            };
             
-            let delta = 1.0 / (substeps as f32 * 90.0);
-            for point in &mut model.points {
-                let disp = point.position - point.prev_position;
-                point.prev_position = point.position;
-                point.acceleration *= delta * delta;
-                point.position += disp + point.acceleration;
-                point.acceleration = Vec2::ZERO;
+            for _ in 0..self.solver_iterations {
+                let (checks, resolved) = self.resolve_collisions(rect, mouse_point);
+                self.collision_checks += checks;
+                self.collisions_resolved += resolved;
+                
+                self.resolve_obstacle_collisions();
+                
+                self.resolve_wall_collisions(rect);
+            }
+
+            if mouse_point.is_some() {
+                self.points.pop();
             }
         }   
     }
 
+    fn substep_delta(&self) -> f32 {
+        self.time_scale * FIXED_STEP / self.active_substeps as f32
+    }
+
+    /// Substeps needed so no point moves further than the smallest radius in one substep, at
+    /// least `substeps` and at most `MAX_SUBSTEPS`.
+    fn needed_substeps(&self) -> usize {
+        let smallest_radius = self.points.iter().map(|p| p.radius).fold(f32::INFINITY, f32::min);
+        // Displacement over a whole step, as the velocities are per active substep.
+        let max_displacement = self.points.iter().map(|p| p.velocity().length()).fold(0.0, f32::max) * self.active_substeps as f32;
+        let needed = (max_displacement / smallest_radius).ceil() as usize;
+        needed.clamp(self.substeps, MAX_SUBSTEPS)
+    }
+
+    /// Changes the substeps per step, rescaling the Verlet velocities to the new substep length.
+    fn set_substeps(&mut self, substeps: usize) {
+        if substeps == self.active_substeps {
+            return;
+        }
+        let factor = self.active_substeps as f32 / substeps as f32;
+        for point in &mut self.points {
+            point.set_velocity(point.velocity() * factor);
+        }
+        self.active_substeps = substeps;
+    }
+
+    /// Changes the time scale, rescaling the implicit Verlet velocities so motion continues at the
+    /// same real speed relative to the new scale instead of jumping.
+    fn set_time_scale(&mut self, time_scale: f32) {
+        let factor = time_scale / self.time_scale;
+        for point in &mut self.points {
+            point.set_velocity(point.velocity() * factor);
+        }
+        self.time_scale = time_scale;
+    }
+
+    fn integrate(&mut self, delta: f32) {
+        for point in self.points.iter_mut().filter(|p| !p.pinned && !p.asleep) {
+            let velocity = point.velocity();
+            point.prev_position = point.position;
+            point.acceleration *= delta * delta;
+            point.position += velocity + point.acceleration;
+            point.acceleration = Vec2::ZERO;
+        }
+    }
+
     fn view(app: &App, model: &Model, frame: Frame) {
-        let draw = app.draw();
+        let draw = model.camera.transform(&app.draw());
         draw.background().color(BLACK);
 
-        for point in &model.points {
+        let stride = model.max_drawn.map_or(1, |max_drawn| model.points.len().div_ceil(max_drawn.max(1)).max(1));
+
+        if model.show_trails {
+            for (point, trail) in model.points.iter().zip(&model.trails).step_by(stride) {
+                let (r, g, b) = (point.color.red as f32, point.color.green as f32, point.color.blue as f32);
+                let trail = trail.iter().take(model.trail_length).enumerate().map(|(i, position)| {
+                    let alpha = 1.0 - i as f32 / model.trail_length as f32;
+                    (position, rgba(r, g, b, alpha))
+                });
+                draw.polyline()
+                    .weight(2.0)
+                    .points_colored(trail);
+            }
+        }
+
+        let built_tree;
+        let quad_tree = match &model.cached_tree {
+            Some(tree) => tree,
+            None => {
+                built_tree = model.build_tree(app.window_rect());
+                &built_tree
+            },
+        };
+
+        // Hue per cluster of points within contact distance of the largest points, spread by the
+        // golden ratio so consecutive clusters differ.
+        let mut cluster_hues = Vec::new();
+        if model.cluster_coloring {
+            cluster_hues = vec![0.0; model.points.len()];
+            let clusters = model.build_index_tree(app.window_rect()).connected_components(&model.points, model.maximum_size * 2.0);
+            for (i, cluster) in clusters.iter().enumerate() {
+                for &index in cluster {
+                    cluster_hues[index] = (i as f32 * 0.618_034).fract();
+                }
+            }
+        }
+
+        for (index, point) in model.points.iter().enumerate().step_by(stride) {
+            let color: Srgb = if model.cluster_coloring {
+                hsv(cluster_hues[index], 0.8, 1.0).into()
+            } else if model.density_coloring {
+                // Hue runs from blue for isolated points to red at DENSITY_SATURATION neighbors.
+                let neighbors = quad_tree.count_radius(point.position.x, point.position.y, model.density_radius).saturating_sub(1);
+                let density = (neighbors as f32 / DENSITY_SATURATION).min(1.0);
+                hsv(0.66 * (1.0 - density), 1.0, 1.0).into()
+            } else {
+                point.color.into_format()
+            };
+            let axes = point.semi_axes();
             draw.ellipse()
                 .xy(point.position)
-                .radius(point.radius)
+                .w_h(axes.x * 2.0, axes.y * 2.0)
                 .resolution(12.0)
-                .color(point.color);
+                .color(color);
+            if point.pinned {
+                draw.ellipse()
+                    .xy(point.position)
+                    .radius(point.radius * 0.4)
+                    .resolution(12.0)
+                    .color(BLACK);
+            }
         }
 
-        draw.ellipse()
-            .xy(app.mouse.position())
-            .radius(model.mouse_radius)
-            .color(WHITE);
+        if let Container::Circle{ center, radius } = model.container {
+            draw.ellipse()
+                .xy(center)
+                .radius(radius)
+                .no_fill()
+                .stroke(GRAY)
+                .stroke_weight(2.0);
+        }
 
-        let width = app.window_rect().w();
-        let height = app.window_rect().h();
+        if model.show_mesh {
+            // The pairs are searched lazily, so the search stops at the last line drawn.
+            let radius = model.mesh_radius;
+            let pairs = quad_tree.iter().flat_map(|a| {
+                quad_tree.iter_radius(a.position.x, a.position.y, radius)
+                    .filter(move |b| b.id > a.id && a.position.distance_squared(b.position) <= radius * radius)
+                    .map(move |b| (a, b))
+            });
+            for (a, b) in pairs.take(MAX_MESH_LINES) {
+                draw.line()
+                    .start(a.position)
+                    .end(b.position)
+                    .color(rgba(1.0, 1.0, 1.0, 0.2));
+            }
+        }
 
-        let quad_tree = QuadTree::from_points(model.points.clone(), -width/2.0, -height/2.0, width, height, model.points_per_quad);
-        
-        let mouse_pos = app.mouse.position();
+        if let Some(centroid) = quad_tree.centroid().filter(|_| model.show_centroid) {
+            draw.ellipse()
+                .xy(centroid)
+                .radius(6.0)
+                .color(rgba(1.0, 0.0, 1.0, 1.0))
+                .z(60.0);
+        }
+
+        for (center, radius) in &model.obstacles {
+            draw.ellipse()
+                .xy(*center)
+                .radius(*radius)
+                .color(GRAY);
+        }
+
+        let mouse_pos = model.camera.screen_to_world(app.mouse.position());
 
-        let query = quad_tree.query_radius(mouse_pos.x, mouse_pos.y, model.mouse_radius + model.maximum_size);
+        draw.ellipse()
+            .xy(mouse_pos)
+            .radius(model.mouse_radius_world(app.main_window().scale_factor()))
+            .color(WHITE);
+
+        let query = quad_tree.query_radius(mouse_pos.x, mouse_pos.y, model.mouse_radius_world(app.main_window().scale_factor()) + model.maximum_size);
         for p in query {
             draw.line()
                 .start(mouse_pos)
                 .end(p.position)
                 .color(WHITE);
         }
-        
+
+        if model.show_query_trace {
+            let (visited, pruned) = quad_tree.trace_radius(mouse_pos.x, mouse_pos.y, model.mouse_radius_world(app.main_window().scale_factor()) + model.maximum_size);
+            for (x, y, width, height) in visited {
+                draw.rect()
+                    .x_y(x + width / 2.0, y + height / 2.0)
+                    .w_h(width, height)
+                    .color(rgba(0.2, 0.6, 1.0, 0.25));
+            }
+            for (x, y, width, height) in pruned {
+                draw.rect()
+                    .x_y(x + width / 2.0, y + height / 2.0)
+                    .w_h(width, height)
+                    .stroke(rgba(1.0, 0.3, 0.3, 0.6))
+                    .stroke_weight(1.0)
+                    .z(60.0)
+                    .no_fill();
+            }
+        }
 
         if model.show_quad_tree {
-            quad_tree.draw_quad_tree_outlines(&draw);
+            quad_tree.draw_quad_tree_outlines(&draw, model.graded_outlines, model.skip_empty_quads);
         }
 
         draw.to_frame(app, &frame).unwrap();
-        model.egui.draw_to_frame(&frame).unwrap();
+        if let Some(egui) = &model.egui {
+            egui.draw_to_frame(&frame).unwrap();
+        }
     }
 
     fn raw_window_event(_app: &App, model: &mut Model, event: &nannou::winit::event::WindowEvent) {
-        model.egui.handle_raw_event(event);
+        if let Some(egui) = &mut model.egui {
+            egui.handle_raw_event(event);
+        }
     }
 
-    fn resolve_mouse_collisions(&mut self, app: &App) {
-        let mouse_pos = app.mouse.position();
-        self.points.iter_mut().for_each(|point| {
+    fn mouse_pressed(_app: &App, model: &mut Model, button: MouseButton) {
+        if button == MouseButton::Left && !model.egui.as_ref().is_some_and(|egui| egui.ctx().is_pointer_over_area()) {
+            model.click_pending = true;
+        }
+    }
+
+    /// Moves every point that travelled further than its radius during the last integration back
+    /// to its earliest contact along the path and reflects the approaching part of its velocity.
+    /// The other points are treated as fixed at their new positions.
+    fn resolve_swept_collisions(&mut self, rect: Rect) {
+        let quadtree = self.build_index_tree(rect);
+        let largest_radius = self.points.iter().map(|p| p.radius).fold(0.0, f32::max);
+        for i in 0..self.points.len() {
+            let point = &self.points[i];
+            let (start, end) = (point.prev_position, point.position);
+            if point.pinned || start.distance(end) <= point.radius {
+                continue;
+            }
+            let others = quadtree.query_segment(start, end, point.radius + largest_radius).into_iter()
+                .filter(|&j| j != i)
+                .map(|j| (self.points[j].position, self.points[j].radius));
+            let contact = others.chain(self.obstacles.iter().copied())
+                .filter_map(|(center, radius)| time_of_impact(start, end, center, point.radius + radius).map(|t| (t, center)))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            if let Some((t, center)) = contact {
+                let point = &mut self.points[i];
+                let position = start.lerp(end, t);
+                let normal = (position - center).normalize_or_zero();
+                let velocity = end - start;
+                let approach = velocity.dot(normal);
+                point.position = position;
+                point.prev_position = position - (velocity - normal * approach * (1.0 + point.restitution));
+            }
+        }
+    }
+
+    /// Pushes points fully out of the obstacles, which never move themselves.
+    fn resolve_obstacle_collisions(&mut self) {
+        for point in self.points.iter_mut().filter(|p| !p.pinned) {
+            for (center, radius) in &self.obstacles {
+                let axis = point.position - *center;
+                let dist = axis.length();
+                let extent = point.extent(axis);
+                if dist < extent + radius {
+                    point.position += axis.normalize_or_zero() * (extent + radius - dist);
+                }
+            }
+        }
+    }
+
+    /// Mouse radius in world units. With `mouse_radius_in_pixels` the radius is given in physical
+    /// pixels and divided by the window's `scale_factor` and the camera scale.
+    fn mouse_radius_world(&self, scale_factor: f32) -> f32 {
+        if self.mouse_radius_in_pixels {
+            self.mouse_radius / scale_factor / self.camera.scale
+        } else {
+            self.mouse_radius
+        }
+    }
+
+    /// Immovable stand-in for the mouse, added after the real points for the collision resolution.
+    fn mouse_point(mouse_pos: Vec2, mouse_radius: f32) -> Point {
+        let mut point = Point::new(usize::MAX, mouse_pos, mouse_pos, Vec2::ZERO, mouse_radius, Default::default());
+        point.pinned = true;
+        point
+    }
+
+    fn resolve_mouse_collisions(&mut self, mouse_pos: Vec2, mouse_radius: f32) {
+        self.points.iter_mut().filter(|p| !p.pinned).for_each(|point| {
             let axis = point.position - mouse_pos;
             let dist = axis.length();
-            if dist <= point.radius + self.mouse_radius {
-                let delta = point.radius + self.mouse_radius - dist;
-                let norm = axis.normalize() * delta * 0.5;
+            let extent = point.extent(axis);
+            if dist <= extent + mouse_radius {
+                let delta = extent + mouse_radius - dist;
+                let norm = axis.normalize_or_zero() * delta * 0.5;
                 point.position += norm;
             }
         });
     }
 
-    fn update_egui(&mut self, update: Update) {
-        let ctx = self.egui.begin_frame();
+    fn update_egui(&mut self, update: Update, rect: Rect) {
+        let mut preset = None;
+        let mut time_scale = self.time_scale;
+        let mut start_recording = false;
+        let mut stop_recording = false;
+        let mut replay = false;
+        let mut export_csv = false;
+        let bucket_size = (self.points_per_quad / 8).max(1);
+        let histogram = self.show_histogram.then(|| self.build_tree(rect).occupancy_histogram(bucket_size));
+        let (kinetic_energy, potential_energy) = self.energy(rect);
+        let Some(egui) = &mut self.egui else {
+            return;
+        };
+        let ctx = egui.begin_frame();
         nannou_egui::egui::Window::new("Quad Tree").show(&ctx, |ui| {
             ui.heading("Settings");
             if ui.button("Clear").clicked() {
                 self.points.clear();
+                self.trails.clear();
+                self.tree_dirty = true;
             }
             if ui.button("Toggle Simulation").clicked() {
                 self.running = !self.running;
             }
+            if ui.button("Freeze Motion").clicked() {
+                for point in &mut self.points {
+                    point.set_velocity(Vec2::ZERO);
+                    point.acceleration = Vec2::ZERO;
+                }
+            }
+            ui.horizontal(|ui| {
+                ui.label("Presets:");
+                if ui.button("Default").clicked() {
+                    preset = Some(ModelConfig::default());
+                }
+                if ui.button("Sparse").clicked() {
+                    preset = Some(ModelConfig::sparse());
+                }
+                if ui.button("Dense").clicked() {
+                    preset = Some(ModelConfig::dense());
+                }
+            });
             ui.checkbox(&mut self.show_quad_tree, "Show Quad Tree");
-            ui.add(nannou_egui::egui::Slider::new(&mut self.points_per_quad, 1..=1000).logarithmic(true).text("Points per Quad"));
+            ui.checkbox(&mut self.graded_outlines, "Color Quads by Depth");
+            ui.checkbox(&mut self.skip_empty_quads, "Hide Empty Quads");
+            ui.checkbox(&mut self.show_trails, "Show Trails");
+            let mut cap_drawn = self.max_drawn.is_some();
+            let mut max_drawn = self.max_drawn.unwrap_or(DEFAULT_MAX_DRAWN);
+            ui.checkbox(&mut cap_drawn, "Cap Drawn Points");
+            ui.add_enabled(cap_drawn, nannou_egui::egui::Slider::new(&mut max_drawn, 100..=100_000).logarithmic(true).text("Max Drawn"));
+            self.max_drawn = cap_drawn.then_some(max_drawn);
+            ui.add(nannou_egui::egui::Slider::new(&mut self.trail_length, 2..=TRAIL_CAPACITY).text("Trail Length"));
+            ui.checkbox(&mut self.show_query_trace, "Show Query Pruning");
+            ui.checkbox(&mut self.density_coloring, "Color by Neighbor Count");
+            ui.checkbox(&mut self.cluster_coloring, "Color by Cluster");
+            ui.add(nannou_egui::egui::Slider::new(&mut self.density_radius, 1.0..=200.0).logarithmic(true).text("Neighbor Radius"));
+            ui.checkbox(&mut self.show_mesh, "Show Mesh");
+            ui.checkbox(&mut self.show_centroid, "Show Centroid");
+            ui.add(nannou_egui::egui::Slider::new(&mut self.mesh_radius, 1.0..=200.0).logarithmic(true).text("Mesh Radius"));
+            if ui.add(nannou_egui::egui::Slider::new(&mut self.points_per_quad, 1..=1000).logarithmic(true).text("Points per Quad")).changed() {
+                self.tree_dirty = true;
+            }
             ui.add(nannou_egui::egui::Slider::new(&mut self.mouse_radius, 1.0..=100.0).text("Mouse Radius"));
+            ui.checkbox(&mut self.mouse_radius_in_pixels, "Mouse Radius in Pixels");
+            ui.checkbox(&mut self.mouse_in_tree, "Mouse in Tree");
             ui.add(nannou_egui::egui::Slider::new(&mut self.minimum_size, 5.0..=self.maximum_size - 0.1).logarithmic(true).text("Minimum Size"));
             ui.add(nannou_egui::egui::Slider::new(&mut self.maximum_size, self.minimum_size+0.1..=100.0).logarithmic(true).text("Maximum Size"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.solver_iterations, 1..=8).text("Solver Iterations"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.substeps, 1..=MAX_SUBSTEPS).text("Substeps"));
+            ui.checkbox(&mut self.auto_substeps, "Auto Substeps");
+            ui.label(format!("Active Substeps: {}", self.active_substeps));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.rest_threshold, 0.0..=1.0).text("Rest Threshold"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.stiffness, 0.0..=1.0).text("Stiffness"));
+            ui.checkbox(&mut self.leaf_based_collisions, "Leaf Based Collisions");
+            ui.checkbox(&mut self.bottom_up_collisions, "Bottom Up Collisions");
+            ui.checkbox(&mut self.parallel, "Parallel Broad Phase");
+            ui.checkbox(&mut self.continuous_collisions, "Continuous Collisions");
+            ui.add(nannou_egui::egui::Slider::new(&mut time_scale, 0.1..=4.0).logarithmic(true).text("Time Scale"));
+            ui.checkbox(&mut self.fixed_timestep, "Fixed Timestep");
+            ui.add(nannou_egui::egui::Slider::new(&mut self.gravity_magnitude, 0.0..=5000.0).text("Gravity"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.gravity_angle, -180.0..=180.0).text("Gravity Angle"));
             ui.horizontal(|ui| {
                 ui.label("Spawner Mode:");
                 ui.radio_value(&mut self.spawner_mode, SpawnerMode::Inactive, "Inactive");
                 ui.radio_value(&mut self.spawner_mode, SpawnerMode::TopLeft, "Top Left");
             });
+            ui.add(nannou_egui::egui::Slider::new(&mut self.spawn_rate, 1.0..=1000.0).logarithmic(true).text("Spawn Rate (points/s)"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.anti_coincidence_jitter, 0.0..=1.0).text("Spawn Jitter"));
+            ui.checkbox(&mut self.elliptical_points, "Spawn Ellipses");
+            ui.checkbox(&mut self.use_palette, "Palette Colors");
+            ui.horizontal(|ui| {
+                for color in &mut self.palette {
+                    let mut rgb = [color.red, color.green, color.blue];
+                    if ui.color_edit_button_rgb(&mut rgb).changed() {
+                        *color = Srgb::new(rgb[0], rgb[1], rgb[2]);
+                    }
+                }
+                if ui.button("+").clicked() {
+                    self.palette.push(Srgb::new(1.0, 1.0, 1.0));
+                }
+                if ui.button("-").clicked() && self.palette.len() > 1 {
+                    self.palette.pop();
+                }
+            });
+            ui.add(nannou_egui::egui::Slider::new(&mut self.minimum_restitution, 0.0..=self.maximum_restitution).text("Minimum Restitution"));
+            ui.add(nannou_egui::egui::Slider::new(&mut self.maximum_restitution, self.minimum_restitution..=1.0).text("Maximum Restitution"));
+            ui.horizontal(|ui| {
+                ui.label("Click Mode:");
+                ui.radio_value(&mut self.click_mode, ClickMode::Nothing, "Nothing");
+                ui.radio_value(&mut self.click_mode, ClickMode::PlaceObstacle, "Place Obstacle");
+                ui.radio_value(&mut self.click_mode, ClickMode::SpawnPoint, "Spawn Point");
+                ui.radio_value(&mut self.click_mode, ClickMode::TogglePin, "Toggle Pin");
+            });
+            ui.checkbox(&mut self.inherit_mouse_velocity, "Spawn with Mouse Velocity");
+            ui.add(nannou_egui::egui::Slider::new(&mut self.obstacle_radius, 2.0..=100.0).text("Obstacle Radius"));
+            if ui.button("Clear Obstacles").clicked() {
+                self.obstacles.clear();
+            }
+            let mut circular = matches!(self.container, Container::Circle{ .. });
+            if ui.checkbox(&mut circular, "Circular Container").changed() {
+                self.container = if circular {
+                    Container::Circle{ center: rect.xy(), radius: rect.w().min(rect.h()) / 2.0 }
+                } else {
+                    Container::Rect
+                };
+            }
+            if let Container::Circle{ radius, .. } = &mut self.container {
+                ui.add(nannou_egui::egui::Slider::new(radius, 50.0..=rect.w().min(rect.h()) / 2.0).text("Container Radius"));
+            }
             ui.label(format!("Frame time: {:.2} ms", update.since_last.as_secs_f64() * 1000.0));
+            ui.label(format!("Simulated time: {:.2} s", self.simulated_time));
+            ui.label(format!("Collision checks: {}, resolved: {}", self.collision_checks, self.collisions_resolved));
+            ui.label(format!("Landings: {}", self.landings));
+            ui.label(format!("Kinetic energy: {:.3e}", kinetic_energy));
+            ui.label(format!("Potential energy: {:.3e}", potential_energy));
+            ui.label(format!("Total energy: {:.3e}", kinetic_energy + potential_energy));
+            let stats = &self.session_stats;
+            ui.label(format!("Session: {} frames, max {} points, max depth {}", stats.frames, stats.max_points, stats.max_depth));
+            ui.label(format!("Average points: {:.1}, average collisions resolved: {:.1}", stats.average_points(), stats.average_collisions_resolved()));
+            ui.label(format!("Total collisions resolved: {}", stats.total_collisions_resolved));
+            if ui.button("Reset Stats").clicked() {
+                self.session_stats = SessionStats::default();
+            }
+            ui.checkbox(&mut self.show_histogram, "Show Leaf Occupancy");
+            if let Some(histogram) = &histogram {
+                ui.label(format!("Leaves per {} points", bucket_size));
+                Model::draw_histogram(ui, histogram);
+            }
+            ui.horizontal(|ui| {
+                ui.label("Recording:");
+                ui.text_edit_singleline(&mut self.recording_path);
+            });
+            ui.horizontal(|ui| {
+                if self.recording.is_some() {
+                    stop_recording = ui.button("Stop Recording").clicked();
+                } else {
+                    start_recording = ui.button("Start Recording").clicked();
+                }
+                replay = ui.button("Replay").clicked();
+            });
+            ui.label(format!("Seed: {}", self.seed));
+            ui.checkbox(&mut self.log_positions, "Log Positions to positions.csv");
+            export_csv = ui.button("Export CSV").clicked();
+            if let Some(status) = &self.export_status {
+                ui.label(status);
+            }
         });
+        drop(ctx);
+        if time_scale != self.time_scale {
+            self.set_time_scale(time_scale);
+        }
+        if let Some(config) = preset {
+            self.apply_config(&config, rect);
+        }
+        if start_recording {
+            self.start_recording(self.recording_path.clone(), rect);
+        }
+        if stop_recording {
+            if let Err(err) = self.stop_recording() {
+                eprintln!("Failed to write recording: {}", err);
+            }
+        }
+        if replay {
+            if let Err(err) = self.replay(self.recording_path.clone(), rect) {
+                eprintln!("Failed to replay recording: {}", err);
+            }
+        }
+        if export_csv {
+            match export_points_csv(&self.points) {
+                Ok(path) => self.export_status = Some(format!("Exported points to {}", path.display())),
+                Err(err) => {
+                    eprintln!("Failed to export points: {}", err);
+                    self.export_status = Some(format!("Failed to export points: {}", err));
+                }
+            }
+        }
     }
 
-    fn spawn_point(&mut self, position: Vec2) {
-        let random_color = nannou::color::rgb(rand::random(), rand::random(), rand::random());
-        let random_radius = random_range(self.minimum_size, self.maximum_size);
-        self.points.push(Point::new(self.points.len(), position, position + Vec2::new(-2.0, 0.0), Vec2::ZERO, random_radius, random_color));
-    }
-
-    fn resolve_collisions(&mut self, app: &App) {
-        let quadtree = QuadTree::from_points(self.points.clone(), app.window_rect().left(), app.window_rect().bottom(), app.window_rect().w(), app.window_rect().h(), self.points_per_quad);
-        let max_radius = self.maximum_size;
-        self.points.par_iter_mut().for_each(|point| {
-            let const_point = point.clone();
-            quadtree.query_radius(const_point.position.x, const_point.position.y, point.radius + max_radius)
-                .into_iter()
-                .for_each(|p| {
-                    let axis = const_point.position - p.position;
-                    let dist = axis.x * axis.x + axis.y * axis.y;
-                    if p.id != const_point.id && dist <= (point.radius + p.radius) * (point.radius + p.radius) {
-                        let delta = point.radius + p.radius - dist.sqrt();
-                        let norm = axis.normalize() * delta * 0.5;
-                        point.position += norm;
-                    }
-                });
-            }); 
+    fn draw_histogram(ui: &mut nannou_egui::egui::Ui, histogram: &[usize]) {
+        let (response, painter) = ui.allocate_painter(nannou_egui::egui::vec2(200.0, 60.0), nannou_egui::egui::Sense::hover());
+        let area = response.rect;
+        let max = histogram.iter().copied().max().unwrap_or(0).max(1);
+        let bar_width = area.width() / histogram.len().max(1) as f32;
+        for (i, &count) in histogram.iter().enumerate() {
+            let left = area.left() + i as f32 * bar_width;
+            let top = area.bottom() - area.height() * count as f32 / max as f32;
+            let bar = nannou_egui::egui::Rect::from_min_max(nannou_egui::egui::pos2(left, top), nannou_egui::egui::pos2(left + bar_width - 1.0, area.bottom()));
+            painter.rect_filled(bar, 0.0, nannou_egui::egui::Color32::LIGHT_BLUE);
+        }
     }
 
-    fn resolve_wall_collisions(&mut self, gravity: f32, app: &App) {
-        let left = app.window_rect().left();
-        let right = app.window_rect().right();
-        let bottom = app.window_rect().bottom();
-        self.points.iter_mut().for_each(|point| {
-            point.acceleration = Vec2::new(0.0, gravity);
-        
-            if point.position.y - point.radius < bottom {
-                let y_diff = point.position.y - point.prev_position.y;
-                point.acceleration.y = 0.0;
-                point.position.y = bottom + point.radius;
-                point.prev_position.y = point.position.y + y_diff * 0.5;
-            }
-            if point.position.x - point.radius < left || point.position.x + point.radius > right {
-                let x_diff = point.position.x - point.prev_position.x;
-                if point.position.x - point.radius < left {
-                    point.position.x = left + point.radius;
+    /// Adds a point at `position`. `occupied` is an index tree over the points before the spawn, a
+    /// position coinciding with one of them is jittered by `anti_coincidence_jitter`.
+    fn spawn_point(&mut self, position: Vec2, occupied: Option<&IndexQuadTree>) {
+        let mut position = position;
+        let coincides = |tree: &IndexQuadTree| tree.any_within(&self.points, position.x, position.y, 0.0, None);
+        if self.anti_coincidence_jitter > 0.0 && occupied.is_some_and(coincides) {
+            let jitter = self.anti_coincidence_jitter;
+            position += Vec2::new(self.rng.gen_range(-jitter..=jitter), self.rng.gen_range(-jitter..=jitter));
+        }
+        // The random color is drawn either way, so the palette doesn't shift the rest of the RNG sequence.
+        let random_color = nannou::color::rgb(self.rng.gen(), self.rng.gen(), self.rng.gen());
+        let color = if self.use_palette && !self.palette.is_empty() {
+            let color = self.palette[self.palette_index % self.palette.len()];
+            self.palette_index = (self.palette_index + 1) % self.palette.len();
+            nannou::color::rgb(color.red as f64, color.green as f64, color.blue as f64)
+        } else {
+            random_color
+        };
+        let random_radius = self.rng.gen_range(self.minimum_size..self.maximum_size);
+        let mut point = Point::new(self.points.len(), position, position + Vec2::new(-2.0, 0.0), Vec2::ZERO, random_radius, color);
+        point.spawn_generation = self.generation;
+        if self.maximum_restitution > self.minimum_restitution {
+            point.restitution = self.rng.gen_range(self.minimum_restitution..=self.maximum_restitution);
+        } else {
+            point.restitution = self.minimum_restitution;
+        }
+        if self.elliptical_points {
+            // The random radius stays the larger semi-axis, so the broad phase bound still holds.
+            let minor = random_radius * self.rng.gen_range(0.5..1.0);
+            if self.rng.gen() {
+                point.set_ellipse(random_radius, minor);
+            } else {
+                point.set_ellipse(minor, random_radius);
+            }
+        }
+        self.points.push(point);
+        self.tree_dirty = true;
+    }
+
+    /// Keeps `cached_tree` up to date while paused, so `view` doesn't rebuild the tree of
+    /// unchanged points every frame. While running the points move every frame and `view` builds
+    /// its own tree instead.
+    fn refresh_tree_cache(&mut self, rect: Rect) {
+        let bounds_changed = self.cached_tree.as_ref().is_none_or(|tree| tree.bounds() != tree_bounds(rect));
+        if self.running {
+            self.cached_tree = None;
+        } else if self.tree_dirty || bounds_changed {
+            self.cached_tree = Some(self.build_tree(rect));
+        }
+        self.tree_dirty = false;
+    }
+
+    /// Builds the tree over the window at the current generation. Its bounds come from
+    /// `tree_bounds`, like those of `build_index_tree`, so physics and rendering see the same tree.
+    fn build_tree(&self, rect: Rect) -> QuadTree {
+        let (x, y, width, height) = tree_bounds(rect);
+        let mut tree = QuadTree::from_points(self.points.clone(), x, y, width, height, self.points_per_quad);
+        tree.set_generation(self.generation);
+        tree
+    }
+
+    /// Tree for the coincidence check of `spawn_point`, only built while spawn jitter is on.
+    fn coincidence_tree(&self, rect: Rect) -> Option<IndexQuadTree> {
+        (self.anti_coincidence_jitter > 0.0).then(|| self.build_index_tree(rect))
+    }
+
+    fn build_index_tree(&self, rect: Rect) -> IndexQuadTree {
+        let (x, y, width, height) = tree_bounds(rect);
+        let mut tree = IndexQuadTree::from_points(&self.points, x, y, width, height, self.points_per_quad);
+        tree.set_parallel(self.parallel);
+        tree
+    }
+
+    /// Returns the number of narrow phase checks performed and of overlapping pairs among them
+    /// that were pushed apart. `mouse_point` is the index of the
+    /// point standing in for the mouse, which is never resting and may be larger than
+    /// `maximum_size`, so its pairs come from a query around it instead of the pair search.
+    fn resolve_collisions(&mut self, rect: Rect, mouse_point: Option<usize>) -> (usize, usize) {
+        let quadtree = self.build_index_tree(rect);
+        self.tree_depth = quadtree.stats().max_depth;
+        // Each overlapping pair is pushed apart by equal and opposite corrections exactly once.
+        // Corrections are summed in the deterministic pair order and applied afterwards, so the
+        // outcome does not depend on the order of the points.
+        let stiffness = self.stiffness;
+        let bounce_threshold = RESTITUTION_THRESHOLD * self.substep_delta();
+        // Without the mouse point, the mouse pushes a point by half the overlap once per substep.
+        // The mouse point takes part in every solver iteration, so its partner only takes the share
+        // that leaves the same half of the overlap after all of them.
+        let mouse_share = ((1.0 - 0.5_f32.powf(1.0 / self.solver_iterations as f32)) / stiffness).min(1.0);
+        let mut corrections = vec![Vec2::ZERO; self.points.len()];
+        let mut checks = 0;
+        let mut resolved = 0;
+        let mut pairs = if self.leaf_based_collisions {
+            quadtree.leaf_pairs()
+        } else {
+            quadtree.pairs_within(&self.points, self.maximum_size * 2.0)
+        };
+        if let Some(mouse) = mouse_point {
+            let (position, radius) = (self.points[mouse].position, self.points[mouse].radius);
+            pairs.retain(|&(i, j)| i != mouse && j != mouse);
+            pairs.extend(quadtree.query_radius(position.x, position.y, radius + self.maximum_size).into_iter().filter(|&i| i != mouse).map(|i| (i, mouse)));
+        }
+        // Points moving less than the threshold are resting, and a resting point with candidate
+        // partners that all rest as well is asleep. Pairs of sleeping points are dropped before the
+        // narrow phase, and `integrate` leaves sleeping points in place so a settled pile doesn't
+        // sink while nothing holds it up. A point without partners has no pairs to drop and stays
+        // awake, so it falls once its support moves away. A threshold of 0 disables the fast path.
+        let threshold = self.rest_threshold;
+        let resting: Vec<bool> = self.points.iter().enumerate().map(|(i, p)| threshold > 0.0 && Some(i) != mouse_point && p.velocity().length() < threshold).collect();
+        let mut partnered = vec![false; self.points.len()];
+        let mut woken = vec![false; self.points.len()];
+        for &(i, j) in &pairs {
+            partnered[i] = true;
+            partnered[j] = true;
+            woken[i] |= !resting[j];
+            woken[j] |= !resting[i];
+        }
+        let asleep: Vec<bool> = (0..self.points.len()).map(|i| resting[i] && partnered[i] && !woken[i]).collect();
+        for (point, &asleep) in self.points.iter_mut().zip(&asleep) {
+            point.asleep = asleep;
+        }
+        pairs.retain(|&(i, j)| !(asleep[i] && asleep[j]));
+        // Bottom up, pairs are resolved from the lowest point of the pair upwards and applied right
+        // away, so a correction near the floor is already visible to the pairs stacked above it.
+        // Summed corrections would make the order irrelevant.
+        let bottom_up = self.bottom_up_collisions;
+        if bottom_up {
+            let lower_y = |(i, j): (usize, usize)| self.points[i].position.y.min(self.points[j].position.y);
+            pairs.sort_by(|&a, &b| lower_y(a).total_cmp(&lower_y(b)));
+        }
+        for (i, j) in pairs {
+            let (a, b) = (&self.points[i], &self.points[j]);
+            checks += 1;
+            let axis = a.position - b.position;
+            let dist = axis.length();
+            let contact_distance = a.extent(axis) + b.extent(axis);
+            if dist <= contact_distance {
+                resolved += 1;
+                // A pinned point doesn't move, so its partner takes the whole correction.
+                let (share_a, share_b) = match (a.pinned, b.pinned) {
+                    _ if Some(j) == mouse_point => (if a.pinned { 0.0 } else { mouse_share }, 0.0),
+                    (false, false) => (0.5, 0.5),
+                    (true, false) => (0.0, 1.0),
+                    (false, true) => (1.0, 0.0),
+                    (true, true) => (0.0, 0.0),
+                };
+                let normal = axis.normalize_or_zero();
+                let mut norm = normal * (contact_distance - dist) * stiffness;
+                // The projection alone stops the approach, like a restitution of 0. The bounce adds
+                // the larger restitution of the pair times the approach speed on top. The mouse
+                // doesn't bounce points, with or without the mouse point.
+                let approach = (b.velocity() - a.velocity()).dot(normal);
+                if approach > bounce_threshold && Some(j) != mouse_point {
+                    norm += normal * approach * a.restitution.max(b.restitution);
+                }
+                if bottom_up {
+                    self.points[i].position += norm * share_a;
+                    self.points[j].position -= norm * share_b;
                 } else {
-                    point.position.x = right - point.radius;
+                    corrections[i] += norm * share_a;
+                    corrections[j] -= norm * share_b;
                 }
-                point.prev_position.x = point.position.x + x_diff * 0.5;
+            }
+        }
+        for (point, correction) in self.points.iter_mut().zip(corrections) {
+            point.position += correction;
+        }
+        (checks, resolved)
+    }
+
+    fn gravity(&self) -> Vec2 {
+        let angle = self.gravity_angle.to_radians();
+        Vec2::new(angle.sin(), -angle.cos()) * self.gravity_magnitude
+    }
+
+    /// Shrinks a circular container to fit inside the window, the area the tree is built over, so
+    /// no point inside the container falls outside the tree.
+    fn fit_container(&mut self, rect: Rect) {
+        if let Container::Circle{ center, radius } = &mut self.container {
+            let fit = (center.x - rect.left()).min(rect.right() - center.x).min(center.y - rect.bottom()).min(rect.top() - center.y);
+            *radius = radius.min(fit.max(0.0));
+        }
+    }
+
+    /// Keeps the points inside the window, or the circle of a circular container. Gravity is
+    /// applied as the base acceleration, minus the part pushing a point into a wall it touches.
+    fn resolve_wall_collisions(&mut self, rect: Rect) {
+        let gravity = self.gravity();
+        if let Container::Circle{ center, radius } = self.container {
+            self.resolve_circle_collisions(gravity, center, radius);
+            return;
+        }
+        let left = rect.left();
+        let right = rect.right();
+        let bottom = rect.bottom();
+        self.points.iter_mut().filter(|p| !p.pinned).for_each(|point| {
+            point.acceleration = gravity;
+            let axes = point.semi_axes();
+        
+            // Walls run once per solver iteration, so a point pushed back past a wall after it was
+            // already bounced away from it keeps moving away instead of being reflected again.
+            if point.position.y - axes.y < bottom {
+                let velocity = point.velocity();
+                point.acceleration.y = point.acceleration.y.max(0.0);
+                point.position.y = bottom + axes.y;
+                point.floor_contact = true;
+                let velocity_y = if velocity.y < 0.0 { -velocity.y * point.restitution } else { velocity.y };
+                point.set_velocity(Vec2::new(velocity.x, velocity_y));
+            }
+            if point.position.x - axes.x < left || point.position.x + axes.x > right {
+                let velocity = point.velocity();
+                let into_wall = if point.position.x - axes.x < left {
+                    point.acceleration.x = point.acceleration.x.max(0.0);
+                    point.position.x = left + axes.x;
+                    velocity.x < 0.0
+                } else {
+                    point.acceleration.x = point.acceleration.x.min(0.0);
+                    point.position.x = right - axes.x;
+                    velocity.x > 0.0
+                };
+                let velocity_x = if into_wall { -velocity.x * point.restitution } else { velocity.x };
+                point.set_velocity(Vec2::new(velocity_x, velocity.y));
             }
         });
     }
+
+    /// Keeps the points inside the circle, pushing them back along the radial direction. Like the
+    /// rectangular walls, the outward velocity is reflected scaled by the point's restitution.
+    fn resolve_circle_collisions(&mut self, gravity: Vec2, center: Vec2, radius: f32) {
+        self.points.iter_mut().filter(|p| !p.pinned).for_each(|point| {
+            point.acceleration = gravity;
+
+            let offset = point.position - center;
+            let max_dist = (radius - point.radius).max(0.0);
+            if offset.length() > max_dist {
+                let normal = offset.normalize_or_zero();
+                let velocity = point.velocity();
+                let outward = velocity.dot(normal).max(0.0);
+                point.position = center + normal * max_dist;
+                point.set_velocity(velocity - normal * outward * (1.0 + point.restitution));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quad_tree::quadtree::{diff_points, MIN_RADIUS};
+
+    fn window() -> Rect {
+        Rect::from_w_h(800.0, 800.0)
+    }
+
+    /// A frame of `FIXED_STEP` without clicking, with the mouse far outside the window.
+    fn idle_frame() -> FrameInput {
+        FrameInput { since_last: FIXED_STEP, mouse: Vec2::splat(1e4), clicked: false }
+    }
+
+    /// Runs `frames` idle frames and returns the collision checks summed over them.
+    fn run(model: &mut Model, frames: usize) -> usize {
+        (0..frames).map(|_| {
+            model.advance(idle_frame(), window(), 1.0);
+            model.collision_checks
+        }).sum()
+    }
+
+    fn pile() -> Model {
+        let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 10, ..Default::default() };
+        Model::from_config(&config, 7, window())
+    }
+
+    #[test]
+    fn model_from_custom_config() {
+        let config = ModelConfig { points_per_quad: 7, maximum_size: 20.0, initial_grid: 5, ..ModelConfig::sparse() };
+        let model = Model::from_config(&config, 1, window());
+        assert_eq!(model.points_per_quad, 7);
+        assert_eq!(model.spawner_mode, SpawnerMode::Inactive);
+        assert_eq!(model.points.len(), 25);
+        assert!(model.points.iter().all(|p| p.radius >= config.minimum_size && p.radius < config.maximum_size));
+    }
+
+    #[test]
+    fn resting_pile_needs_fewer_checks_without_sinking() {
+        let (mut all, mut fast_path) = (pile(), pile());
+        run(&mut all, 300);
+        run(&mut fast_path, 300);
+        fast_path.rest_threshold = 0.5;
+        let all_checks = run(&mut all, 60);
+        let fast_path_checks = run(&mut fast_path, 60);
+        // Both count narrow phase evaluations, which the fast path drops for sleeping pairs.
+        assert!(fast_path_checks * 2 < all_checks, "{} vs {}", fast_path_checks, all_checks);
+        assert!(fast_path.points.iter().any(|p| p.asleep));
+        // Without friction the full pile keeps spreading out slowly, so compare heights only.
+        let height = |model: &Model| model.points.iter().map(|p| p.position.y).sum::<f32>() / model.points.len() as f32;
+        assert!(height(&fast_path) > height(&all) - 0.5, "sleeping pile sank to {} vs {}", height(&fast_path), height(&all));
+    }
+
+    #[test]
+    fn sleeping_point_falls_once_its_support_moves() {
+        let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, minimum_size: 10.0, maximum_size: 10.001, rest_threshold: 0.5, ..Default::default() };
+        let mut model = Model::from_config(&config, 3, window());
+        for i in 0..2 {
+            model.spawn_point(Vec2::new(0.0, window().bottom() + 10.0 + 20.0 * i as f32), None);
+            model.points.last_mut().unwrap().set_velocity(Vec2::ZERO);
+        }
+        run(&mut model, 60);
+        assert!(model.points.iter().all(|p| p.asleep));
+        model.points[0].set_velocity(Vec2::new(5.0, 0.0));
+        run(&mut model, 120);
+        let top = model.points[1].position.y;
+        assert!(top < window().bottom() + 11.0, "top point stayed at {}", top);
+    }
+
+    #[test]
+    fn stack_of_ten_stays_put() {
+        let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, minimum_size: 10.0, maximum_size: 10.001, ..Default::default() };
+        let mut model = Model::from_config(&config, 3, window());
+        for i in 0..10 {
+            model.spawn_point(Vec2::new(0.0, window().bottom() + 10.0 + 20.0 * i as f32), None);
+            model.points.last_mut().unwrap().set_velocity(Vec2::ZERO);
+        }
+        let top = model.points[9].position.y;
+        run(&mut model, 100);
+        let drift = (model.points[9].position.y - top).abs();
+        assert!(drift < 1.0, "top ball drifted by {}", drift);
+    }
+
+    #[test]
+    fn fixed_timestep_tracks_wall_clock() {
+        let mut model = pile();
+        model.fixed_timestep = true;
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut wall_clock = 0.0;
+        for _ in 0..500 {
+            // Up to three steps per frame, below `MAX_STEPS_PER_FRAME`, so no time is dropped.
+            let since_last = rng.gen_range(0.0..FIXED_STEP * 3.0);
+            wall_clock += since_last as f64;
+            model.advance(FrameInput { since_last, ..idle_frame() }, window(), 1.0);
+            let lag = wall_clock - model.simulated_time;
+            assert!((-1e-4..FIXED_STEP as f64 + 1e-4).contains(&lag), "simulated time lags by {}", lag);
+        }
+    }
+
+    #[test]
+    fn dropped_point_lands_once() {
+        let mut model = pile();
+        model.points.clear();
+        model.fixed_timestep = true;
+        model.spawn_point(Vec2::new(0.0, 100.0), None);
+        // Three frames per physics step, as on a 270 Hz display.
+        for _ in 0..600 {
+            model.advance(FrameInput { since_last: FIXED_STEP / 3.0, ..idle_frame() }, window(), 1.0);
+        }
+        assert_eq!(model.landings, 1);
+    }
+
+    #[test]
+    fn replay_reproduces_recording() {
+        let path = std::env::temp_dir().join("quad_tree_replay_round_trip.txt");
+        let scripted = |frame: usize| FrameInput {
+            since_last: 1.0 / 60.0,
+            mouse: Vec2::new((frame as f32 * 0.05).cos(), (frame as f32 * 0.05).sin()) * 300.0,
+            clicked: frame.is_multiple_of(40),
+        };
+        let mut model = pile();
+        model.start_recording(&path, window());
+        for frame in 0..150 {
+            let input = model.next_input(scripted(frame));
+            model.advance(input, window(), 1.0);
+        }
+        model.stop_recording().unwrap();
+        let recorded = model.points.clone();
+
+        // Settings left changed in the UI must not leak into the replay.
+        model.substeps = 7;
+        model.auto_substeps = true;
+        model.click_mode = ClickMode::PlaceObstacle;
+        model.container = Container::Circle { center: Vec2::ZERO, radius: 200.0 };
+        model.elliptical_points = true;
+        model.bottom_up_collisions = true;
+        model.mouse_in_tree = true;
+        model.maximum_restitution = 1.0;
+        model.replay(&path, window()).unwrap();
+        for _ in 0..150 {
+            let input = model.next_input(idle_frame());
+            model.advance(input, window(), 1.0);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(model.points.len(), recorded.len());
+        assert!(diff_points(&recorded, &model.points, 0.0).is_empty());
+    }
+
+    #[test]
+    fn circular_container_fits_the_window() {
+        let mut model = pile();
+        model.container = Container::Circle { center: Vec2::ZERO, radius: 1000.0 };
+        run(&mut model, 60);
+        assert_eq!(model.container, Container::Circle { center: Vec2::ZERO, radius: 400.0 });
+        assert!(model.points.iter().all(|p| window().contains(p.position)));
+        // The pile settles into the bowl, with the outer points resting against the lower arc.
+        run(&mut model, 300);
+        assert!(model.points.iter().all(|p| p.position.y < 0.0 && p.position.length() + p.radius <= 400.5));
+        let on_arc = model.points.iter().filter(|p| p.position.length() + p.radius > 399.5).count();
+        assert!(on_arc >= 10, "{} points touch the arc", on_arc);
+    }
+
+    #[test]
+    fn gravity_angle_survives_zero_magnitude() {
+        let mut model = pile();
+        model.gravity_angle = 90.0;
+        model.gravity_magnitude = 0.0;
+        assert_eq!(model.gravity(), Vec2::ZERO);
+        model.gravity_magnitude = GRAVITY;
+        assert!(model.gravity().abs_diff_eq(Vec2::new(GRAVITY, 0.0), 1e-3));
+    }
+
+    #[test]
+    fn tilted_gravity_slides_points_toward_the_right_wall() {
+        let mut model = pile();
+        model.gravity_angle = 45.0;
+        run(&mut model, 300);
+        let right = window().right();
+        assert!(model.points.iter().all(|p| p.position.x > 0.0), "a point stayed on the left half");
+        let corner = model.points.iter().filter(|p| p.position.x + p.radius > right - 0.5 && p.position.y - p.radius < window().bottom() + 0.5).count();
+        assert_eq!(corner, 1, "exactly one point should sit in the bottom right corner");
+    }
+
+    #[test]
+    fn mouse_point_pushes_like_the_mouse() {
+        let push = |mouse_in_tree: bool| {
+            let mut model = pile();
+            model.mouse_in_tree = mouse_in_tree;
+            model.gravity_magnitude = 0.0;
+            model.substeps = 1;
+            let start = Vec2::new(0.0, 0.0);
+            model.points = vec![Point::new(0, start, start, Vec2::ZERO, 10.0, Default::default())];
+            let mouse = Vec2::new(model.mouse_radius_world(1.0) + 4.0, 0.0);
+            model.advance(FrameInput { mouse, ..idle_frame() }, window(), 1.0);
+            start - model.points[0].position
+        };
+        let (old, new) = (push(false), push(true));
+        assert!(old.x > 0.0);
+        assert!(old.abs_diff_eq(new, 1e-3), "mouse pushed by {} without and {} with the mouse point", old, new);
+    }
+
+    #[test]
+    fn position_log_appends_every_point() {
+        let path = std::env::temp_dir().join("quad_tree_positions.csv");
+        let _ = fs::remove_file(&path);
+        let mut points = pile().points;
+        points[0].position = Vec2::new(5000.0, 0.0);
+        append_positions_csv(&path, 1, &points).unwrap();
+        append_positions_csv(&path, 2, &points[..1]).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), points.len() + 1);
+        assert_eq!(lines[0], format!("1,{},5000,0", points[0].id));
+        assert!(lines[points.len()].starts_with("2,"));
+    }
+
+    #[test]
+    fn session_stats_aggregate_frames() {
+        let mut stats = SessionStats::default();
+        for (points, depth, resolved) in [(10, 2, 4), (30, 5, 0), (20, 3, 8)] {
+            stats.record(points, depth, resolved);
+        }
+        assert_eq!((stats.frames, stats.max_points, stats.max_depth, stats.total_collisions_resolved), (3, 30, 5, 12));
+        assert_eq!((stats.average_points(), stats.average_collisions_resolved()), (20.0, 4.0));
+        assert_eq!(SessionStats::default().average_points(), 0.0);
+    }
+
+    #[test]
+    fn resolved_collisions_are_counted_among_the_checks() {
+        let mut model = pile();
+        let checks = run(&mut model, 300);
+        let resolved = model.session_stats.total_collisions_resolved;
+        assert!(resolved > 0);
+        assert!(resolved <= checks as u64);
+    }
+
+    #[test]
+    fn physics_and_render_trees_share_bounds() {
+        let model = pile();
+        for rect in [window(), Rect::from_x_y_w_h(150.0, -40.0, 640.0, 480.0)] {
+            assert_eq!(model.build_tree(rect).bounds(), tree_bounds(rect));
+            assert_eq!(model.build_index_tree(rect).bounds(), tree_bounds(rect));
+        }
+    }
+
+    #[test]
+    fn zero_radius_point_leaves_neighbors_intact() {
+        let mut model = pile();
+        model.gravity_magnitude = 0.0;
+        let (near, coincident) = (Vec2::new(10.0, 0.0), Vec2::new(-30.0, 0.0));
+        model.points = vec![
+            Point::new(0, Vec2::ZERO, Vec2::ZERO, Vec2::ZERO, 0.0, Default::default()),
+            Point::new(1, near, near, Vec2::ZERO, 10.0, Default::default()),
+            Point::new(2, coincident, coincident, Vec2::ZERO, 10.0, Default::default()),
+            Point::new(3, coincident, coincident, Vec2::ZERO, 0.0, Default::default()),
+        ];
+        run(&mut model, 30);
+        assert!(model.points.iter().all(|p| p.position.is_finite()));
+        // The clamped points still separate from their neighbors, even from a coincident start.
+        assert!(model.points[0].position.distance(model.points[1].position) >= MIN_RADIUS + 10.0 - 0.1);
+        assert!(model.points[2].position.distance(model.points[3].position) >= MIN_RADIUS + 10.0 - 0.1);
+    }
+
+    #[test]
+    fn collision_result_does_not_depend_on_ids() {
+        let resolve = |ids: [usize; 3]| {
+            let mut model = pile();
+            let positions = [Vec2::new(0.0, 0.0), Vec2::new(12.0, 3.0), Vec2::new(5.0, 14.0)];
+            model.points = ids.iter().zip(positions).map(|(&id, p)| Point::new(id, p, p, Vec2::ZERO, 10.0, Default::default())).collect();
+            model.resolve_collisions(window(), None);
+            model.points.iter().map(|p| p.position).collect::<Vec<_>>()
+        };
+        let swapped = resolve([1, 0, 2]);
+        assert_eq!(resolve([0, 1, 2]), swapped);
+        assert_ne!(swapped[0], Vec2::ZERO);
+        // Equal and opposite corrections leave the center of the points in place.
+        let center: Vec2 = swapped.iter().sum::<Vec2>() / 3.0;
+        assert!(center.abs_diff_eq(Vec2::new(17.0, 17.0) / 3.0, 1e-4));
+    }
+
+    #[test]
+    fn bouncier_points_bounce_higher() {
+        let bounce_height = |restitution: f32| {
+            let mut model = pile();
+            let start = Vec2::new(0.0, 200.0);
+            let mut point = Point::new(0, start, start, Vec2::ZERO, 10.0, Default::default());
+            point.restitution = restitution;
+            model.points = vec![point];
+            let mut landed = false;
+            let mut height = f32::NEG_INFINITY;
+            for _ in 0..240 {
+                model.advance(idle_frame(), window(), 1.0);
+                let p = &model.points[0];
+                landed |= p.position.y - p.radius <= window().bottom() + 0.5;
+                if landed {
+                    height = height.max(p.position.y);
+                }
+            }
+            height - window().bottom()
+        };
+        let (low, high) = (bounce_height(0.1), bounce_height(0.9));
+        assert!(high > 2.0 * low + 50.0, "bounced to {} with 0.1 and {} with 0.9", low, high);
+    }
+
+    #[test]
+    fn fast_point_does_not_tunnel_through_a_thin_obstacle() {
+        let launch = |continuous_collisions: bool| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, minimum_size: 2.0, maximum_size: 2.001, ..Default::default() };
+            let mut model = Model::from_config(&config, 5, window());
+            model.continuous_collisions = continuous_collisions;
+            model.gravity_magnitude = 0.0;
+            model.obstacles.push((Vec2::ZERO, 3.0));
+            model.spawn_point(Vec2::new(-60.0, 0.0), None);
+            model.points[0].set_velocity(Vec2::new(50.0, 0.0));
+            run(&mut model, 3);
+            model.points[0].position.x
+        };
+        assert!(launch(false) > 0.0, "the test point should tunnel without continuous collisions");
+        let x = launch(true);
+        assert!(x < 0.0, "point passed through the obstacle to x = {}", x);
+    }
+
+    #[test]
+    fn csv_rows_list_every_field_in_order() {
+        let still = Point::new(3, Vec2::new(1.5, -2.0), Vec2::new(1.5, -2.0), Vec2::ZERO, 4.0, Srgb::new(1.0, 0.5, 0.0));
+        assert_eq!(point_csv_row(&still), "3,1.5,-2,1.5,-2,4,1,0.5,0\n");
+        let moving = Point::new(12, Vec2::new(10.0, 20.25), Vec2::new(9.0, 20.0), Vec2::ZERO, 0.5, Srgb::new(0.25, 0.75, 1.0));
+        assert_eq!(point_csv_row(&moving), "12,10,20.25,9,20,0.5,0.25,0.75,1\n");
+    }
+
+    #[test]
+    fn trails_are_only_recorded_while_shown() {
+        let mut model = pile();
+        run(&mut model, 3);
+        assert!(model.trails.is_empty());
+        model.show_trails = true;
+        run(&mut model, 3);
+        assert_eq!(model.trails.len(), model.points.len());
+        assert!(model.trails.iter().zip(&model.points).all(|(trail, point)| trail.iter().next() == Some(point.position)));
+        assert_eq!(model.trails[0].iter().count(), 3);
+        model.show_trails = false;
+        run(&mut model, 1);
+        assert!(model.trails.is_empty());
+    }
+
+    #[test]
+    fn camera_round_trips_between_screen_and_world() {
+        let camera = View { offset: Vec2::new(120.0, -45.5), scale: 2.5 };
+        for world in [Vec2::ZERO, Vec2::new(-300.0, 250.0), Vec2::new(17.25, -0.5)] {
+            assert!(camera.screen_to_world(camera.world_to_screen(world)).abs_diff_eq(world, 1e-3));
+        }
+        // The offset is shown at the center of the window, and a world unit spans `scale` screen units.
+        assert_eq!(camera.world_to_screen(camera.offset), Vec2::ZERO);
+        assert!(camera.world_to_screen(camera.offset + Vec2::X).abs_diff_eq(Vec2::new(2.5, 0.0), 1e-5));
+        assert!(View::IDENTITY.screen_to_world(Vec2::new(3.0, 4.0)).abs_diff_eq(Vec2::new(3.0, 4.0), 1e-6));
+    }
+
+    #[test]
+    fn bottom_up_ordering_settles_a_stack_sooner() {
+        // Frames until the fastest point stays below 0.1 per substep for 10 frames, and how far the
+        // top of a stack of 15 sank below its starting height by then.
+        let settle = |bottom_up: bool| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, minimum_size: 10.0, maximum_size: 10.001, ..Default::default() };
+            let mut model = Model::from_config(&config, 3, window());
+            model.bottom_up_collisions = bottom_up;
+            for i in 0..15 {
+                model.spawn_point(Vec2::new(0.0, window().bottom() + 10.0 + 20.0 * i as f32), None);
+                model.points.last_mut().unwrap().set_velocity(Vec2::ZERO);
+            }
+            let top = model.points[14].position.y;
+            let mut still = 0;
+            for frame in 0..300 {
+                run(&mut model, 1);
+                let speed = model.points.iter().map(|p| p.velocity().length()).fold(0.0, f32::max);
+                still = if speed < 0.1 { still + 1 } else { 0 };
+                if still == 10 {
+                    return (frame - 9, top - model.points[14].position.y);
+                }
+            }
+            panic!("stack did not settle");
+        };
+        let (unordered_frames, unordered_sag) = settle(false);
+        let (ordered_frames, ordered_sag) = settle(true);
+        assert!(ordered_frames < unordered_frames, "{} vs {} frames", ordered_frames, unordered_frames);
+        assert!(ordered_sag < unordered_sag, "{} vs {} sag", ordered_sag, unordered_sag);
+    }
+
+    #[test]
+    fn paused_spawns_are_in_the_cached_tree() {
+        let config = ModelConfig { initial_grid: 4, ..Default::default() };
+        let mut model = Model::from_config(&config, 9, window());
+        model.running = false;
+        for _ in 0..10 {
+            run(&mut model, 1);
+            let cached = model.cached_tree.as_ref().expect("paused frames keep a tree");
+            assert_eq!(cached.iter().count(), model.points.len());
+            assert!(!model.tree_dirty);
+        }
+        assert!(model.points.len() > 16, "the spawner should keep running while paused");
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release"]
+    fn bench_spawn_check() {
+        // Piles of growing size below the spawner, which checks its positions at the top left.
+        let mut model = pile();
+        model.spawner_mode = SpawnerMode::TopLeft;
+        let spawn_positions = model.spawn_positions(window());
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut times = Vec::new();
+        for n in [1_000, 10_000, 100_000] {
+            let points: Vec<Point> = (0..n).map(|id| {
+                let position = Vec2::new(rng.gen_range(-400.0..400.0), rng.gen_range(-400.0..0.0));
+                Point::new(id, position, position, Vec2::ZERO, 2.0, Srgb::new(1.0, 1.0, 1.0))
+            }).collect();
+            let (x, y, width, height) = tree_bounds(window());
+            let tree = IndexQuadTree::from_points(&points, x, y, width, height, 16);
+            let elapsed = (0..5).map(|_| {
+                let start = std::time::Instant::now();
+                for _ in 0..10_000 {
+                    for pos in &spawn_positions {
+                        std::hint::black_box(tree.any_within(&points, pos.x, pos.y, 32.0, None));
+                    }
+                }
+                start.elapsed()
+            }).min().unwrap();
+            println!("{} points: {:?}", n, elapsed);
+            times.push(elapsed);
+        }
+        assert!(times[2] < times[0] * 4, "the spawn check slowed down from {:?} to {:?}", times[0], times[2]);
+    }
+
+    #[test]
+    fn clicks_on_an_occupied_spot_are_jittered_apart() {
+        let click_twice = |jitter: f32| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, ..Default::default() };
+            let mut model = Model::from_config(&config, 3, window());
+            model.running = false;
+            model.click_mode = ClickMode::SpawnPoint;
+            model.anti_coincidence_jitter = jitter;
+            for _ in 0..2 {
+                model.advance(FrameInput { since_last: 0.0, mouse: Vec2::new(10.0, 20.0), clicked: true }, window(), 1.0);
+            }
+            (model.points[0].position, model.points[1].position)
+        };
+        let (first, second) = click_twice(0.0);
+        assert_eq!(first, second);
+        let (first, second) = click_twice(0.5);
+        assert_eq!(first, Vec2::new(10.0, 20.0));
+        assert!(second != first && (second - first).abs().max_element() <= 0.5, "second click at {:?}", second);
+    }
+
+    #[test]
+    fn population_grows_at_the_spawn_rate() {
+        // Rates low enough that the previous spawns have fallen clear of the spawn positions.
+        for spawn_rate in [15.0, 30.0] {
+            for frame_time in [FIXED_STEP, FIXED_STEP * 3.0] {
+                let config = ModelConfig { spawn_rate, ..Default::default() };
+                let mut model = Model::from_config(&config, 11, window());
+                // Two seconds, however they are split into frames.
+                let frames = (2.0 / frame_time).round() as usize;
+                for _ in 0..frames {
+                    model.advance(FrameInput { since_last: frame_time, ..idle_frame() }, window(), 1.0);
+                }
+                let expected = spawn_rate * 2.0;
+                assert!((model.points.len() as f32 - expected).abs() <= 1.0, "{} points at {} per second and {} s frames", model.points.len(), spawn_rate, frame_time);
+            }
+        }
+    }
+
+    #[test]
+    fn half_time_scale_halves_the_motion() {
+        let displacement = |time_scale: f32, gravity: bool| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, ..Default::default() };
+            let mut model = Model::from_config(&config, 5, window());
+            model.spawn_point(Vec2::ZERO, None);
+            if gravity {
+                model.points[0].set_velocity(Vec2::ZERO);
+            } else {
+                model.gravity_magnitude = 0.0;
+                model.points[0].set_velocity(Vec2::new(2.0, 0.0));
+            }
+            model.set_time_scale(time_scale);
+            run(&mut model, 30);
+            model.points[0].position.length()
+        };
+        // At constant velocity half the simulated time covers half the distance.
+        let ratio = displacement(0.5, false) / displacement(1.0, false);
+        assert!((ratio - 0.5).abs() < 1e-3, "gliding ratio {}", ratio);
+        // Falling from rest covers a distance growing with the square of the simulated time.
+        let ratio = displacement(0.5, true) / displacement(1.0, true);
+        assert!((ratio - 0.25).abs() < 0.02, "falling ratio {}", ratio);
+    }
+
+    #[test]
+    fn points_pile_on_top_of_an_obstacle() {
+        let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, minimum_size: 10.0, maximum_size: 10.001, ..Default::default() };
+        let mut model = Model::from_config(&config, 5, window());
+        model.obstacles.push((Vec2::new(0.0, -100.0), 80.0));
+        for i in 0..3 {
+            model.spawn_point(Vec2::new(0.0, 50.0 + 30.0 * i as f32), None);
+            model.points[i].set_velocity(Vec2::ZERO);
+        }
+        run(&mut model, 300);
+        // Dropped straight onto the top, the column has no sideways push to slide off with.
+        let mut heights: Vec<f32> = model.points.iter().map(|p| p.position.y).collect();
+        heights.sort_by(f32::total_cmp);
+        for (i, height) in heights.iter().enumerate() {
+            let resting = -100.0 + 80.0 + 10.0 + 20.0 * i as f32;
+            assert!((height - resting).abs() < 1.0, "point {} rests at {} instead of {}", i, height, resting);
+        }
+        assert!(model.points.iter().all(|p| p.position.x.abs() < 1e-3));
+    }
+
+    #[test]
+    fn pinned_points_hold_others_up() {
+        let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, minimum_size: 10.0, maximum_size: 10.001, ..Default::default() };
+        let mut model = Model::from_config(&config, 5, window());
+        // A shelf of touching pinned points.
+        let shelf: Vec<Vec2> = (-5..=5).map(|i| Vec2::new(20.0 * i as f32, 0.0)).collect();
+        for (i, position) in shelf.iter().enumerate() {
+            model.spawn_point(*position, None);
+            // Stopped like by the pin click mode.
+            model.points[i].pinned = true;
+            model.points[i].set_velocity(Vec2::ZERO);
+        }
+        // Dropped over the gaps, so they come to rest in the valleys between the shelf points.
+        for x in [-50.0, 10.0, 70.0] {
+            model.spawn_point(Vec2::new(x, 40.0), None);
+            model.points.last_mut().unwrap().set_velocity(Vec2::ZERO);
+        }
+        run(&mut model, 300);
+        for (point, position) in model.points.iter().zip(&shelf) {
+            assert_eq!(point.position, *position, "pinned point {} moved", point.id);
+        }
+        for point in &model.points[shelf.len()..] {
+            assert!(point.position.y > 10.0 && point.position.y < 30.0, "point {} ended at {:?}", point.id, point.position);
+        }
+    }
+
+    #[test]
+    fn fast_point_raises_the_auto_substeps() {
+        let launch = |auto_substeps: bool| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, minimum_size: 2.0, maximum_size: 2.001, ..Default::default() };
+            let mut model = Model::from_config(&config, 5, window());
+            model.auto_substeps = auto_substeps;
+            model.gravity_magnitude = 0.0;
+            model.obstacles.push((Vec2::ZERO, 3.0));
+            model.spawn_point(Vec2::new(-60.0, 0.0), None);
+            run(&mut model, 1);
+            assert_eq!(model.active_substeps, SUBSTEPS, "a slow point needs no extra substeps");
+            // 44 units per step, more than twice the width of the obstacle plus the point.
+            model.points[0].set_velocity(Vec2::new(11.0, 0.0));
+            // The count drops back once the obstacle stopped the point.
+            let most_substeps = (0..3).map(|_| {
+                run(&mut model, 1);
+                model.active_substeps
+            }).max().unwrap();
+            (most_substeps, model.points[0].position.x)
+        };
+        let (substeps, x) = launch(false);
+        assert_eq!(substeps, SUBSTEPS);
+        assert!(x > 0.0, "the test point should tunnel with the manual substeps");
+        let (substeps, x) = launch(true);
+        assert!(substeps > SUBSTEPS, "{} substeps", substeps);
+        assert!(x < 0.0, "point passed through the obstacle to x = {}", x);
+    }
+
+    #[test]
+    fn palette_colors_cycle_in_order() {
+        let spawn = |use_palette: bool| {
+            let config = ModelConfig { spawner_mode: SpawnerMode::Inactive, initial_grid: 0, ..Default::default() };
+            let mut model = Model::from_config(&config, 9, window());
+            model.use_palette = use_palette;
+            model.palette = vec![Srgb::new(1.0, 0.0, 0.0), Srgb::new(0.0, 1.0, 0.0), Srgb::new(0.0, 0.0, 1.0)];
+            for i in 0..7 {
+                model.spawn_point(Vec2::new(40.0 * i as f32, 0.0), None);
+            }
+            model
+        };
+        let model = spawn(true);
+        for (i, point) in model.points.iter().enumerate() {
+            let expected = model.palette[i % 3];
+            assert_eq!(point.color, nannou::color::rgb(expected.red as f64, expected.green as f64, expected.blue as f64), "point {}", i);
+        }
+        assert_eq!(model.palette_index, 1);
+        // Only the colors differ from random coloring, the sizes come from the same RNG sequence.
+        let random = spawn(false);
+        assert!(random.points.iter().zip(&model.points).all(|(a, b)| a.radius == b.radius && a.color != b.color));
+    }
+
+    #[test]
+    fn sequential_runs_with_the_same_seed_are_identical() {
+        let simulate = |parallel: bool| {
+            let mut model = Model::from_config(&ModelConfig::default(), 21, window());
+            model.parallel = parallel;
+            run(&mut model, 240);
+            model.points
+        };
+        let first = simulate(false);
+        let second = simulate(false);
+        assert!(first.len() > 100);
+        assert_eq!(first.len(), second.len());
+        assert!(diff_points(&first, &second, 0.0).is_empty());
+        // The solver sums corrections in pair order, so the parallel pair search doesn't change the outcome either.
+        assert!(diff_points(&first, &simulate(true), 0.0).is_empty());
+    }
 }
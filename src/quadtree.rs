@@ -1,4 +1,8 @@
-use nannou::{color::{rgb, WHITE}, glam::Vec2};
+use std::{collections::{HashMap, HashSet}, ops::ControlFlow};
+
+use nannou::{color::{hsv, rgb, Srgb, WHITE}, glam::Vec2};
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
 #[derive(Clone, Copy, Debug, PartialEq)]    
 pub struct Point {
@@ -8,14 +12,104 @@ pub struct Point {
     pub acceleration: Vec2,
     pub radius: f32,
     pub color: rgb::Rgb<nannou::color::encoding::Srgb, f64>,
+    /// Generation of the tree builds at which the point was spawned, see `QuadTree::generation`.
+    pub spawn_generation: u64,
+    /// Pinned points are never moved by the simulation.
+    pub pinned: bool,
+    pub shape: Shape,
+    /// User data carried along unchanged, e.g. a group id to filter query results by.
+    pub tag: u64,
+    /// Fraction of the approach speed kept as bounce, 0 for no bounce and 1 for fully elastic.
+    pub restitution: f32,
+    /// Set by the simulation when the point touched the bottom wall since the last frame.
+    pub floor_contact: bool,
+    /// Consecutive frames the point rested on the bottom wall.
+    pub floor_frames: u32,
+    /// Set by the simulation while the point and all its neighbors rest, which leaves it in place.
+    pub asleep: bool,
+}
+
+/// Restitution of new points.
+pub const DEFAULT_RESTITUTION: f32 = 0.5;
+
+/// Outline of a point. `Point::radius` always bounds the outline, so radius queries sized by it
+/// find every touching pair regardless of the shape.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Circle,
+    /// Axis aligned ellipse with the given semi-axes.
+    Ellipse{ radius_x: f32, radius_y: f32 },
 }
 
+/// Smallest radius a point can have. `Point::new` clamps zero, negative and NaN radii to it.
+pub const MIN_RADIUS: f32 = 0.5;
+
 impl Point {
     pub fn new(id: usize, position: Vec2, prev_position: Vec2, acceleration: Vec2, radius: f32, color: rgb::Rgb<nannou::color::encoding::Srgb, f64>) -> Point {
-        Point { id, position, prev_position, acceleration, radius, color }
+        let radius = if radius >= MIN_RADIUS { radius } else { MIN_RADIUS };
+        Point { id, position, prev_position, acceleration, radius, color, spawn_generation: 0, pinned: false, shape: Shape::Circle, tag: 0, restitution: DEFAULT_RESTITUTION, floor_contact: false, floor_frames: 0, asleep: false }
+    }
+
+    /// Makes the point an axis aligned ellipse, with `radius` set to the larger semi-axis.
+    pub fn set_ellipse(&mut self, radius_x: f32, radius_y: f32) {
+        let radius_x = if radius_x >= MIN_RADIUS { radius_x } else { MIN_RADIUS };
+        let radius_y = if radius_y >= MIN_RADIUS { radius_y } else { MIN_RADIUS };
+        self.radius = radius_x.max(radius_y);
+        self.shape = Shape::Ellipse{ radius_x, radius_y };
+    }
+
+    /// Semi-axes of the outline along x and y.
+    pub fn semi_axes(&self) -> Vec2 {
+        match self.shape {
+            Shape::Circle => Vec2::splat(self.radius),
+            Shape::Ellipse{ radius_x, radius_y } => Vec2::new(radius_x, radius_y),
+        }
+    }
+
+    /// Distance from the center to the outline in `direction`, at most `radius`. This only
+    /// approximates the contact of two ellipses, which don't touch along the line between centers.
+    pub fn extent(&self, direction: Vec2) -> f32 {
+        let direction = direction.normalize_or_zero();
+        match self.shape {
+            Shape::Ellipse{ radius_x, radius_y } if direction != Vec2::ZERO => {
+                radius_x * radius_y / ((radius_y * direction.x).powi(2) + (radius_x * direction.y).powi(2)).sqrt()
+            },
+            _ => self.radius,
+        }
+    }
+
+    /// Verlet velocity, the displacement over the last step.
+    pub fn velocity(&self) -> Vec2 {
+        self.position - self.prev_position
     }
+
+    /// Moves `prev_position` so that `velocity` returns `velocity`.
+    pub fn set_velocity(&mut self, velocity: Vec2) {
+        self.prev_position = self.position - velocity;
+    }
+}
+
+/// Returns the ids, sorted, of the points that moved further than `tolerance` between the snapshots
+/// `a` and `b`. Points are matched by id, and ids found in only one snapshot count as moved.
+pub fn diff_points(a: &[Point], b: &[Point], tolerance: f32) -> Vec<usize> {
+    let before: HashMap<usize, Vec2> = a.iter().map(|p| (p.id, p.position)).collect();
+    let after: HashMap<usize, Vec2> = b.iter().map(|p| (p.id, p.position)).collect();
+    let mut moved: Vec<usize> = before.iter()
+        .filter(|(id, position)| after.get(id).is_none_or(|other| other.distance(**position) > tolerance))
+        .map(|(&id, _)| id)
+        .chain(after.keys().filter(|id| !before.contains_key(id)).copied())
+        .collect();
+    moved.sort_unstable();
+    moved
 }
 
+/// Points keep their insertion order inside each leaf, also across subdivisions, as long as they
+/// only arrive through `insert` and its variants, `from_points` or `set_points`: splitting a leaf
+/// routes its points with a stable partition. That takes a counting pass per split, which makes
+/// building 200k points about 9% slower at 4 points per quad (114 versus 105 ms) but slightly
+/// faster at 16 and above, see `Node::split`. The order is not kept by `extend` and `from_points_morton`,
+/// which insert in Morton order, nor by a branch that `remove` or `set_capacity` merges back into a
+/// leaf, which concatenates its children in NW, NE, SW, SE order.
 #[derive(Debug)]
 pub struct QuadTree {
     left_x: f32,
@@ -23,171 +117,2763 @@ pub struct QuadTree {
     width: f32,
     height: f32,
     root: Node,
+    generation: u64,
+    max_depth: usize,
+    /// Built by `from_points_loose`, with points stored in every leaf their circle overlaps.
+    loose: bool,
+    /// Points the tree was sized for by `with_capacity`, 0 if unknown. Leaves, the root included,
+    /// reserve their share of it on insertion, assuming an even spread, up to one more than the
+    /// leaf capacity.
+    expected_points: usize,
 }
 
+/// Smallest width and height a tree can cover. Zero, negative and NaN dimensions are clamped to it.
+pub const MIN_SIZE: f32 = 1e-3;
+
+/// Depth at which bulk builds stop subdividing, matching the 16 bit per axis Morton resolution.
+/// Also the default depth limit of `insert`.
+pub const MAX_DEPTH: usize = 16;
+
+/// Most cells `QuadTree::to_uniform_grid` builds, 16M cells of 4 byte offsets.
+pub const MAX_GRID_CELLS: usize = 1 << 24;
+
+/// A branch merges back into a leaf once it holds fewer than `points_per_quad / MERGE_DIVISOR` points.
+pub const MERGE_DIVISOR: usize = 2;
+
 impl QuadTree {
     pub fn new(left_x: f32, bottom_y: f32, width: f32, height: f32) -> QuadTree {
         QuadTree {
-            left_x: left_x,
-            bottom_y: bottom_y,
-            width: width,
-            height: height,
+            left_x,
+            bottom_y,
+            width: QuadTree::clamp_size(width),
+            height: QuadTree::clamp_size(height),
             root: Node::Leaf{ value: Vec::new() },
+            generation: 0,
+            max_depth: MAX_DEPTH,
+            loose: false,
+            expected_points: 0,
         }
     }
 
+    /// Like `new`, but sized for `expected_points`: the root leaf and the leaves created by splits
+    /// reserve their share of it, so bulk insertion reallocates the leaf buffers less often.
+    pub fn with_capacity(left_x: f32, bottom_y: f32, width: f32, height: f32, expected_points: usize) -> QuadTree {
+        let mut tree = QuadTree::new(left_x, bottom_y, width, height);
+        tree.expected_points = expected_points;
+        tree
+    }
+
     pub fn from_points(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> QuadTree {
+        QuadTree::try_from_points(points, left_x, bottom_y, width, height, points_per_quad).0
+    }
+
+    /// Like `from_points`, but with the bounds grown by `margin` on every side, so points on the
+    /// edges of the given bounds lie strictly inside the tree.
+    pub fn from_points_with_margin(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize, margin: f32) -> QuadTree {
+        let margin = margin.max(0.0);
+        QuadTree::from_points(points, left_x - margin, bottom_y - margin, width + margin * 2.0, height + margin * 2.0, points_per_quad)
+    }
+
+    /// Builds the same point set as `from_points`, bottom up: the points are sorted by Morton code
+    /// once, after which every quad is a contiguous run that is split without reinserting. Leaves hold
+    /// at most `points_per_quad` points, except at `MAX_DEPTH` where coincident points stop subdividing.
+    pub fn from_points_morton(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> QuadTree {
         let mut tree = QuadTree::new(left_x, bottom_y, width, height);
-        for point in points.into_iter().filter(|p| p.position.x >= left_x && p.position.x <= left_x + width && p.position.y >= bottom_y && p.position.y <= bottom_y + height) {
-            tree.insert(point, points_per_quad);
+        let mut points: Vec<Point> = points.into_iter().filter(|p| tree.contains(p.position)).collect();
+        points.sort_by_cached_key(|p| tree.morton_code(p.position));
+        let quad = (tree.left_x, tree.bottom_y, tree.width, tree.height);
+        tree.root = QuadTree::build_sorted(&mut points, quad, points_per_quad, 0);
+        tree
+    }
+
+    fn build_sorted(points: &mut [Point], quad: Quad, points_per_quad: usize, depth: usize) -> Node {
+        if points.len() <= points_per_quad || depth >= MAX_DEPTH {
+            return Node::Leaf{ value: points.to_vec() };
+        }
+        // Z-order visits SW, SE, NW, NE. The quantized sort already groups points this way, the stable
+        // re-sort only fixes up points that rounding put on the wrong side of a midline.
+        let z_rank = |p: &Point| match Quadrant::of(quad, p.position) {
+            Quadrant::SW => 0,
+            Quadrant::SE => 1,
+            Quadrant::NW => 2,
+            Quadrant::NE => 3,
+        };
+        points.sort_by_key(z_rank);
+        let (sw, rest) = points.split_at_mut(points.partition_point(|p| z_rank(p) < 1));
+        let (se, rest) = rest.split_at_mut(rest.partition_point(|p| z_rank(p) < 2));
+        let (nw, ne) = rest.split_at_mut(rest.partition_point(|p| z_rank(p) < 3));
+        Node::Branch{
+            nw: Box::new(QuadTree::build_sorted(nw, Quadrant::NW.quad(quad), points_per_quad, depth + 1)),
+            ne: Box::new(QuadTree::build_sorted(ne, Quadrant::NE.quad(quad), points_per_quad, depth + 1)),
+            sw: Box::new(QuadTree::build_sorted(sw, Quadrant::SW.quad(quad), points_per_quad, depth + 1)),
+            se: Box::new(QuadTree::build_sorted(se, Quadrant::SE.quad(quad), points_per_quad, depth + 1)),
+        }
+    }
+
+    /// Loose variant of `from_points` storing every point in all leaves its circle overlaps, so
+    /// radius queries find large points from neighboring leaves without padding the radius by
+    /// the largest point radius. Queries, counts and iteration see each point once, only the per
+    /// leaf views (`query_radius_by_leaf`, `for_each_in_rect_mut` and the leaf statistics) see
+    /// every copy. Not meant for `insert` and `remove`.
+    pub fn from_points_loose(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> QuadTree {
+        let mut tree = QuadTree::new(left_x, bottom_y, width, height);
+        let points: Vec<Point> = points.into_iter().filter(|p| tree.contains(p.position)).collect();
+        let quad = (tree.left_x, tree.bottom_y, tree.width, tree.height);
+        tree.root = QuadTree::build_loose(points, quad, points_per_quad, 0);
+        tree.loose = true;
+        tree
+    }
+
+    fn build_loose(points: Vec<Point>, quad: Quad, points_per_quad: usize, depth: usize) -> Node {
+        if points.len() <= points_per_quad || depth >= MAX_DEPTH {
+            return Node::Leaf{ value: points };
+        }
+        let overlapping = |quadrant: Quadrant| -> Vec<Point> {
+            let (x, y, width, height) = quadrant.quad(quad);
+            points.iter().filter(|p| rect_overlaps((p.position.x - p.radius, p.position.y - p.radius, p.position.x + p.radius, p.position.y + p.radius), x, y, width, height)).cloned().collect()
+        };
+        let children = [Quadrant::NW, Quadrant::NE, Quadrant::SW, Quadrant::SE].map(overlapping);
+        // A child overlapped by every point would split the same points again at every depth.
+        if children.iter().any(|child| child.len() == points.len()) {
+            return Node::Leaf{ value: points };
+        }
+        let [nw, ne, sw, se] = children;
+        Node::Branch{
+            nw: Box::new(QuadTree::build_loose(nw, Quadrant::NW.quad(quad), points_per_quad, depth + 1)),
+            ne: Box::new(QuadTree::build_loose(ne, Quadrant::NE.quad(quad), points_per_quad, depth + 1)),
+            sw: Box::new(QuadTree::build_loose(sw, Quadrant::SW.quad(quad), points_per_quad, depth + 1)),
+            se: Box::new(QuadTree::build_loose(se, Quadrant::SE.quad(quad), points_per_quad, depth + 1)),
+        }
+    }
+
+    /// Like `from_points`, but grows the bounds to take in points lying outside of them instead of
+    /// dropping them. Points with a non-finite position are dropped.
+    pub fn from_points_growing(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> QuadTree {
+        let mut tree = QuadTree::new(left_x, bottom_y, width, height);
+        for point in points {
+            tree.insert_growing(point, points_per_quad);
         }
         tree
     }
 
+    /// Like `from_points`, but also returns the points that were left out for lying outside the bounds.
+    pub fn try_from_points(points: Vec<Point>, left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> (QuadTree, Vec<Point>) {
+        let mut tree = QuadTree::with_capacity(left_x, bottom_y, width, height, points.len());
+        let mut rejected = Vec::new();
+        for point in points {
+            if tree.contains(point.position) {
+                tree.insert(point, points_per_quad);
+            } else {
+                rejected.push(point);
+            }
+        }
+        (tree, rejected)
+    }
+
+    /// Replaces all points, keeping the bounds and reusing the largest leaf buffer for the new root.
+    /// Points outside the bounds are dropped like in `from_points`. Increments the generation.
+    pub fn set_points(&mut self, points: Vec<Point>, points_per_quad: usize) {
+        let mut buffer = std::mem::replace(&mut self.root, Node::Leaf{ value: Vec::new() }).into_largest_buffer();
+        buffer.clear();
+        self.root = Node::Leaf{ value: buffer };
+        self.generation += 1;
+        for point in points {
+            if self.contains(point.position) {
+                self.insert(point, points_per_quad);
+            }
+        }
+    }
+
+    /// Splits the tree into one tree per top level quadrant, in the order NW, NE, SW, SE. The
+    /// points of an unsplit root are routed to the quadrant holding their center.
+    pub fn into_quadrants(self) -> [QuadTree; 4] {
+        let bounds = self.bounds();
+        let [nw, ne, sw, se] = match self.root {
+            Node::Branch{ nw, ne, sw, se } => [*nw, *ne, *sw, *se],
+            Node::Leaf{ value } => {
+                let mut leaves: [Vec<Point>; 4] = Default::default();
+                for point in value {
+                    leaves[Quadrant::of(bounds, point.position) as usize].push(point);
+                }
+                leaves.map(|value| Node::Leaf{ value })
+            },
+        };
+        let subtree = |root, quadrant: Quadrant| {
+            let (left_x, bottom_y, width, height) = quadrant.quad(bounds);
+            QuadTree { left_x, bottom_y, width, height, root, generation: self.generation, max_depth: self.max_depth.saturating_sub(1), loose: self.loose, expected_points: self.expected_points / 4 }
+        };
+        [subtree(nw, Quadrant::NW), subtree(ne, Quadrant::NE), subtree(sw, Quadrant::SW), subtree(se, Quadrant::SE)]
+    }
+
+    /// Indented outline of the tree with one line per node, giving its quadrant and bounds as
+    /// `(x, y, width, height)`, and for leaves the point count and ids.
+    pub fn to_debug_string(&self) -> String {
+        let mut text = String::new();
+        QuadTree::debug_string_rec(&self.root, self.bounds(), "Root", 0, &mut text);
+        text
+    }
+
+    fn debug_string_rec(node: &Node, quad: Quad, label: &str, depth: usize, text: &mut String) {
+        let indent = "  ".repeat(depth);
+        match node {
+            Node::Leaf{ value } => {
+                let ids: Vec<usize> = value.iter().map(|p| p.id).collect();
+                text.push_str(&format!("{}{} leaf {:?}: {} points {:?}\n", indent, label, quad, value.len(), ids));
+            },
+            Node::Branch{ nw, ne, sw, se } => {
+                text.push_str(&format!("{}{} branch {:?}\n", indent, label, quad));
+                QuadTree::debug_string_rec(nw, Quadrant::NW.quad(quad), "NW", depth + 1, text);
+                QuadTree::debug_string_rec(ne, Quadrant::NE.quad(quad), "NE", depth + 1, text);
+                QuadTree::debug_string_rec(sw, Quadrant::SW.quad(quad), "SW", depth + 1, text);
+                QuadTree::debug_string_rec(se, Quadrant::SE.quad(quad), "SE", depth + 1, text);
+            },
+        }
+    }
+
+    /// Bounds of the tree as `(x, y, width, height)`.
+    pub fn bounds(&self) -> Quad {
+        (self.left_x, self.bottom_y, self.width, self.height)
+    }
+
+    fn contains(&self, position: Vec2) -> bool {
+        position.x >= self.left_x && position.x <= self.left_x + self.width && position.y >= self.bottom_y && position.y <= self.bottom_y + self.height
+    }
+
+    fn has_finite_bounds(&self) -> bool {
+        self.left_x.is_finite() && self.bottom_y.is_finite() && (self.left_x + self.width).is_finite() && (self.bottom_y + self.height).is_finite()
+    }
+
+    fn clamp_size(size: f32) -> f32 {
+        if size >= MIN_SIZE { size } else { MIN_SIZE }
+    }
+
+    /// Build counter of the tree, compared against `Point::spawn_generation` to tell fresh points apart.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Depth below which `insert` and `set_capacity` stop splitting leaves, `MAX_DEPTH` by default.
+    /// Leaves at this depth take any number of points, which every query reaching them scans
+    /// linearly, so a lower limit bounds the descent at the cost of slower scans in dense spots.
+    /// Leaves that are already deeper are left as they are.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats { points: 0, leaves: 0, max_depth: 0 };
+        self.root.collect_stats(0, &mut stats);
+        stats
+    }
+
+    /// Coefficient of variation of the points per leaf, empty leaves included: 0 when all leaves
+    /// hold the same number of points and growing with the imbalance. 0 for an empty tree.
+    pub fn balance_factor(&self) -> f32 {
+        let (mut leaves, mut sum, mut sum_squares) = (0.0f64, 0.0f64, 0.0f64);
+        self.try_visit(|_, _, _, _| true, |leaf| {
+            let count = leaf.len() as f64;
+            leaves += 1.0;
+            sum += count;
+            sum_squares += count * count;
+            ControlFlow::Continue(())
+        });
+        if sum == 0.0 {
+            return 0.0;
+        }
+        let mean = sum / leaves;
+        let variance = (sum_squares / leaves - mean * mean).max(0.0);
+        (variance.sqrt() / mean) as f32
+    }
+
+    /// Iterates all points depth first, visiting children in NW, NE, SW, SE order
+    /// and the points of each leaf in insertion order.
+    pub fn iter(&self) -> Iter<'_> {
+        let seen = self.loose.then(HashSet::new);
+        Iter { stack: vec![(&self.root, (self.left_x, self.bottom_y, self.width, self.height))], leaf: [].iter(), rect: None, seen }
+    }
+
+    /// Iterates the points in Z-order of their position, see `morton_code`. Points with the same
+    /// code keep their `iter` order.
+    pub fn iter_morton(&self) -> impl Iterator<Item = &Point> {
+        let mut points: Vec<&Point> = self.iter().collect();
+        points.sort_by_cached_key(|p| self.morton_code(p.position));
+        points.into_iter()
+    }
+
+    /// Ids and positions of all points in `iter` order.
+    pub fn positions(&self) -> Vec<(usize, Vec2)> {
+        self.iter().map(|p| (p.id, p.position)).collect()
+    }
+
+    /// Lazy version of `query_radius`, yielding the same points in the same order. Leaves are only
+    /// entered as the iterator advances, so `take` or `find` stop the traversal early.
+    pub fn iter_radius(&self, x: f32, y: f32, radius: f32) -> Iter<'_> {
+        let rect = (x - radius, y - radius, x + radius, y + radius);
+        let seen = self.loose.then(HashSet::new);
+        Iter { stack: vec![(&self.root, (self.left_x, self.bottom_y, self.width, self.height))], leaf: [].iter(), rect: Some(rect), seen }
+    }
+
     pub fn query_radius(&self, x: f32, y: f32, radius: f32) -> Vec<&Point> {
         let mut result = Vec::new();
-        // QuadTree::query_radius_rec(&self.root, x, y, self.width, self.height, self.left_x, self.bottom_y, radius, &mut result);
+        if self.loose {
+            self.visit_radius_points(x, y, radius, |p| result.push(p));
+        } else {
+            self.visit_radius(x, y, radius, |leaf| result.extend(leaf));
+        }
+        result
+    }
 
-        let mut stack = vec![(&self.root, self.left_x, self.bottom_y, self.width, self.height)];
-        while let Some((node, quad_x, quad_y, width, height)) = stack.pop() {
-            if x + radius < quad_x || x - radius > quad_x + width || y + radius < quad_y || y - radius > quad_y + height {
-                continue;
+    /// Returns the points whose center is within `radius` of `(x, y)`, each with its distance to it.
+    pub fn query_radius_with_distance(&self, x: f32, y: f32, radius: f32) -> Vec<(&Point, f32)> {
+        let center = Vec2::new(x, y);
+        let mut result = Vec::new();
+        self.visit_radius_points(x, y, radius, |p| {
+            let distance_squared = p.position.distance_squared(center);
+            if distance_squared <= radius * radius {
+                result.push((p, distance_squared.sqrt()));
             }
-            match node {
-                Node::Leaf{ value } => {
-                    result.extend(value);
-                },
-                Node::Branch{ nw, ne, sw, se } => {
-                    let width = width / 2.0;
-                    let height: f32 = height / 2.0;
-                    let x_mid = quad_x + width;
-                    let y_mid = quad_y + height;
-                    stack.push((nw, quad_x, y_mid, width, height));
-                    stack.push((ne, x_mid, y_mid, width, height));
-                    stack.push((sw, quad_x, quad_y, width, height));
-                    stack.push((se, x_mid, quad_y, width, height));
-                }
+        });
+        result
+    }
+
+    /// Splits the points around `(x, y)` by distance into those closer than `radius - band` and those
+    /// within `band` of `radius`, in one pass.
+    pub fn query_radius_banded(&self, x: f32, y: f32, radius: f32, band: f32) -> (Vec<&Point>, Vec<&Point>) {
+        let center = Vec2::new(x, y);
+        let band = band.abs();
+        let (mut inside, mut boundary) = (Vec::new(), Vec::new());
+        self.visit_radius_points(x, y, radius + band, |p| {
+            let distance = p.position.distance(center);
+            if distance < radius - band {
+                inside.push(p);
+            } else if distance <= radius + band {
+                boundary.push(p);
+            }
+        });
+        (inside, boundary)
+    }
+
+    /// Like `query_radius`, but traverses the four children of the root in parallel. The results
+    /// come in no particular order. Only worthwhile when the query covers a large part of a big
+    /// tree, for small queries the task overhead outweighs the traversal.
+    pub fn query_radius_par(&self, x: f32, y: f32, radius: f32) -> Vec<&Point> {
+        let Node::Branch{ nw, ne, sw, se } = &self.root else {
+            return self.query_radius(x, y, radius);
+        };
+        let rect = (x - radius, y - radius, x + radius, y + radius);
+        let root = (self.left_x, self.bottom_y, self.width, self.height);
+        let overlaps = |quad_x, quad_y, width, height| rect_overlaps(rect, quad_x, quad_y, width, height);
+        let mut result: Vec<&Point> = [(nw, Quadrant::NW), (ne, Quadrant::NE), (sw, Quadrant::SW), (se, Quadrant::SE)].par_iter().flat_map_iter(|&(child, quadrant)| {
+            let (quad_x, quad_y, width, height) = quadrant.quad(root);
+            let mut result = Vec::new();
+            let _ = QuadTree::try_visit_rec(child, quad_x, quad_y, width, height, &overlaps, &mut |leaf| {
+                result.extend(leaf);
+                ControlFlow::Continue(())
+            });
+            result
+        }).collect();
+        if self.loose {
+            // Copies can sit in different children, so they are only found after joining.
+            let mut seen = HashSet::new();
+            result.retain(|p| seen.insert(p.id));
+        }
+        result
+    }
+
+    /// Like `query_radius`, but with one group per overlapping leaf, in the same order. Empty
+    /// leaves are left out, and in a loose tree a point is part of every group holding a copy.
+    pub fn query_radius_by_leaf(&self, x: f32, y: f32, radius: f32) -> Vec<Vec<&Point>> {
+        let mut result = Vec::new();
+        self.visit_radius(x, y, radius, |leaf| {
+            if !leaf.is_empty() {
+                result.push(leaf.iter().collect());
+            }
+        });
+        result
+    }
+
+    /// Like `query_radius`, but returns copies that don't borrow the tree.
+    pub fn query_radius_owned(&self, x: f32, y: f32, radius: f32) -> Vec<Point> {
+        self.query_radius(x, y, radius).into_iter().copied().collect()
+    }
+
+    /// Like `query_radius`, but only collects the candidates for which `pred` returns true.
+    pub fn query_radius_filter<F: Fn(&Point) -> bool>(&self, x: f32, y: f32, radius: f32, pred: F) -> Vec<&Point> {
+        let mut result = Vec::new();
+        self.visit_radius_points(x, y, radius, |p| if pred(p) { result.push(p) });
+        result
+    }
+
+    /// Returns the points for which `point_included` holds among the leaves whose quads pass
+    /// `quad_overlaps(x, y, width, height)`. Branches failing `quad_overlaps` are pruned, so it has to
+    /// hold for every quad that may contain an included point.
+    pub fn query_custom<P: Fn(f32, f32, f32, f32) -> bool, I: Fn(&Point) -> bool>(&self, quad_overlaps: P, point_included: I) -> Vec<&Point> {
+        let mut result = Vec::new();
+        self.try_visit_points(quad_overlaps, |p| {
+            if point_included(p) {
+                result.push(p);
             }
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    /// Like `query_radius`, but treats the tree bounds as a torus: a circle crossing an edge also
+    /// matches points near the opposite edge. Each point is returned at most once.
+    pub fn query_radius_wrapped(&self, x: f32, y: f32, radius: f32) -> Vec<&Point> {
+        let shift_x = if x + radius > self.left_x + self.width { -self.width } else if x - radius < self.left_x { self.width } else { 0.0 };
+        let shift_y = if y + radius > self.bottom_y + self.height { -self.height } else if y - radius < self.bottom_y { self.height } else { 0.0 };
+
+        let mut centers = vec![(x, y)];
+        if shift_x != 0.0 {
+            centers.push((x + shift_x, y));
+        }
+        if shift_y != 0.0 {
+            centers.push((x, y + shift_y));
+        }
+        if shift_x != 0.0 && shift_y != 0.0 {
+            centers.push((x + shift_x, y + shift_y));
+        }
+
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+        for (x, y) in centers {
+            self.visit_radius_points(x, y, radius, |p| if seen.insert(p.id) { result.push(p) });
+        }
+        result
+    }
+
+    /// Returns every unordered pair of points whose centers are at most `distance` apart, exactly once
+    /// with the lower id first. The order only depends on the tree contents, not on thread scheduling.
+    pub fn pairs_within(&self, distance: f32) -> Vec<(&Point, &Point)> {
+        let points: Vec<&Point> = self.iter().collect();
+        points.par_iter().flat_map_iter(|&a| {
+            self.query_radius_filter(a.position.x, a.position.y, distance, |b| b.id > a.id && a.position.distance_squared(b.position) <= distance * distance)
+                .into_iter()
+                .map(move |b| (a, b))
+        }).collect()
+    }
+
+    /// Like `query_radius`, but only returns points spawned after generation `generation`.
+    pub fn query_radius_newer_than(&self, x: f32, y: f32, radius: f32, generation: u64) -> Vec<&Point> {
+        self.query_radius_filter(x, y, radius, |p| p.spawn_generation > generation)
+    }
+
+    /// Returns the points inside the box centered at `center` with the given half extents,
+    /// rotated counter-clockwise by `angle` radians.
+    pub fn query_obb(&self, center: Vec2, half_extents: Vec2, angle: f32) -> Vec<&Point> {
+        let mut result = Vec::new();
+        if angle == 0.0 {
+            let (min, max) = (center - half_extents, center + half_extents);
+            self.visit_rect_points(min.x, min.y, max.x, max.y, |p| {
+                if p.position.cmpge(min).all() && p.position.cmple(max).all() {
+                    result.push(p);
+                }
+            });
+            return result;
         }
 
+        let (sin, cos) = angle.sin_cos();
+        let aabb_half = Vec2::new(
+            half_extents.x * cos.abs() + half_extents.y * sin.abs(),
+            half_extents.x * sin.abs() + half_extents.y * cos.abs(),
+        );
+        let (min, max) = (center - aabb_half, center + aabb_half);
+        self.visit_rect_points(min.x, min.y, max.x, max.y, |p| {
+            // Project onto the box axes, which is the point-in-box case of the separating axis test.
+            let offset = p.position - center;
+            let local = Vec2::new(offset.x * cos + offset.y * sin, offset.y * cos - offset.x * sin);
+            if local.x.abs() <= half_extents.x && local.y.abs() <= half_extents.y {
+                result.push(p);
+            }
+        });
         result
     }
 
-    fn query_radius_rec<'a>(node: &'a Node, x: f32, y: f32, width: f32, height: f32, quad_x: f32, quad_y: f32, radius: f32, result: &mut Vec<&'a Point>) {
-        let x_right = quad_x + width;
-        let y_top = quad_y + height;
-        if x + radius < quad_x || x - radius > x_right || y + radius < quad_y || y - radius > y_top {
+    /// Returns whether any point other than `exclude_id` has its center within `radius` of `(x, y)`.
+    /// Stops at the first match.
+    pub fn any_within(&self, x: f32, y: f32, radius: f32, exclude_id: Option<usize>) -> bool {
+        let center = Vec2::new(x, y);
+        let mut found = false;
+        self.try_visit_rect(x - radius, y - radius, x + radius, y + radius, |leaf| {
+            found = leaf.iter().any(|p| Some(p.id) != exclude_id && p.position.distance_squared(center) <= radius * radius);
+            if found { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        found
+    }
+
+    /// Calls `f` on every point with its center in the rectangle with bottom left corner `(x, y)`.
+    /// Points stay in their leaf, so moving them out of its quad requires a rebuild. In a loose tree
+    /// `f` is called once per stored copy.
+    pub fn for_each_in_rect_mut<F: FnMut(&mut Point)>(&mut self, x: f32, y: f32, width: f32, height: f32, mut f: F) {
+        let rect = (x, y, x + width, y + height);
+        let quad = (self.left_x, self.bottom_y, self.width, self.height);
+        QuadTree::for_each_in_rect_mut_rec(&mut self.root, quad, rect, &mut f);
+    }
+
+    fn for_each_in_rect_mut_rec(node: &mut Node, quad: Quad, rect: (f32, f32, f32, f32), f: &mut impl FnMut(&mut Point)) {
+        let (quad_x, quad_y, width, height) = quad;
+        if !rect_overlaps(rect, quad_x, quad_y, width, height) {
             return;
         }
         match node {
             Node::Leaf{ value } => {
-                result.extend(value);
+                let (min_x, min_y, max_x, max_y) = rect;
+                value.iter_mut()
+                    .filter(|p| p.position.x >= min_x && p.position.x <= max_x && p.position.y >= min_y && p.position.y <= max_y)
+                    .for_each(f);
             },
-            Node::Branch{ nw, ne, sw, se } => {                
-                let width = width / 2.0;
-                let height: f32 = height / 2.0;
-                let x_mid = quad_x + width;
-                let y_mid = quad_y + height;
-                QuadTree::query_radius_rec(nw, x, y, width, height, quad_x, y_mid, radius, result);
-                QuadTree::query_radius_rec(ne, x, y, width, height, x_mid, y_mid, radius, result);
-                QuadTree::query_radius_rec(sw, x, y, width, height, quad_x, quad_y, radius, result);
-                QuadTree::query_radius_rec(se, x, y, width, height, x_mid, quad_y, radius, result);
+            Node::Branch{ nw, ne, sw, se } => {
+                QuadTree::for_each_in_rect_mut_rec(nw, Quadrant::NW.quad(quad), rect, f);
+                QuadTree::for_each_in_rect_mut_rec(ne, Quadrant::NE.quad(quad), rect, f);
+                QuadTree::for_each_in_rect_mut_rec(sw, Quadrant::SW.quad(quad), rect, f);
+                QuadTree::for_each_in_rect_mut_rec(se, Quadrant::SE.quad(quad), rect, f);
+            },
+        }
+    }
+
+    /// Returns whether no point has its center in the rectangle with bottom left corner `(x, y)`.
+    /// Stops at the first point found.
+    pub fn is_region_empty(&self, x: f32, y: f32, width: f32, height: f32) -> bool {
+        let (max_x, max_y) = (x + width, y + height);
+        let mut empty = true;
+        self.try_visit_rect(x, y, max_x, max_y, |leaf| {
+            empty = !leaf.iter().any(|p| p.position.x >= x && p.position.x <= max_x && p.position.y >= y && p.position.y <= max_y);
+            if empty { ControlFlow::Continue(()) } else { ControlFlow::Break(()) }
+        });
+        empty
+    }
+
+    /// Returns the points with a radius of at least `min_radius` whose center is in the rectangle
+    /// with bottom left corner `(x, y)`. Smaller points are skipped while visiting the leaves.
+    pub fn query_rect_filter_radius(&self, x: f32, y: f32, width: f32, height: f32, min_radius: f32) -> Vec<&Point> {
+        let (max_x, max_y) = (x + width, y + height);
+        let mut result = Vec::new();
+        self.visit_rect_points(x, y, max_x, max_y, |p| {
+            if p.radius >= min_radius && p.position.x >= x && p.position.x <= max_x && p.position.y >= y && p.position.y <= max_y {
+                result.push(p);
+            }
+        });
+        result
+    }
+
+    /// Counts the points whose center is within `radius` of `(x, y)`.
+    pub fn count_radius(&self, x: f32, y: f32, radius: f32) -> usize {
+        let center = Vec2::new(x, y);
+        let mut count = 0;
+        self.visit_radius_points(x, y, radius, |p| count += (p.position.distance_squared(center) <= radius * radius) as usize);
+        count
+    }
+
+    /// Counts the points per concentric shell around `(x, y)`, where shell `i` holds the centers
+    /// at a distance in `[i * shell_width, (i + 1) * shell_width)`.
+    pub fn radial_profile(&self, x: f32, y: f32, shell_width: f32, shells: usize) -> Vec<usize> {
+        let mut counts = vec![0; shells];
+        if shells == 0 || shell_width <= 0.0 {
+            return counts;
+        }
+        let center = Vec2::new(x, y);
+        let radius = shell_width * shells as f32;
+        self.visit_radius_points(x, y, radius, |p| {
+            let shell = (p.position.distance(center) / shell_width) as usize;
+            if shell < shells {
+                counts[shell] += 1;
+            }
+        });
+        counts
+    }
+
+    /// Returns `(min_x, min_y, max_x, max_y)` of the centers within `radius` of `(x, y)`, or `None` if there are none.
+    pub fn radius_bounds(&self, x: f32, y: f32, radius: f32) -> Option<(f32, f32, f32, f32)> {
+        let center = Vec2::new(x, y);
+        let mut bounds: Option<(Vec2, Vec2)> = None;
+        self.visit_radius(x, y, radius, |leaf| {
+            for p in leaf.iter().filter(|p| p.position.distance_squared(center) <= radius * radius) {
+                bounds = Some(match bounds {
+                    Some((min, max)) => (min.min(p.position), max.max(p.position)),
+                    None => (p.position, p.position),
+                });
+            }
+        });
+        bounds.map(|(min, max)| (min.x, min.y, max.x, max.y))
+    }
+
+    /// Picks a uniformly random stored point and returns a position uniformly distributed in the
+    /// disc of radius `jitter` around it, or `None` if the tree is empty.
+    pub fn sample_near_points(&self, rng: &mut impl Rng, jitter: f32) -> Option<Vec2> {
+        let count = self.iter().count();
+        if count == 0 {
+            return None;
+        }
+        let point = self.iter().nth(rng.gen_range(0..count))?;
+        let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+        let distance = jitter * rng.gen::<f32>().sqrt();
+        Some(point.position + Vec2::new(angle.cos(), angle.sin()) * distance)
+    }
+
+    /// Counts leaves by occupancy: index `i` holds the number of leaves with `[i * bucket_size, (i + 1) * bucket_size)` points.
+    /// A `bucket_size` of 0 is treated as 1.
+    pub fn occupancy_histogram(&self, bucket_size: usize) -> Vec<usize> {
+        let bucket_size = bucket_size.max(1);
+        let mut histogram = Vec::new();
+        self.visit_leaves(|leaf| {
+            let bucket = leaf.len() / bucket_size;
+            if bucket >= histogram.len() {
+                histogram.resize(bucket + 1, 0);
             }
+            histogram[bucket] += 1;
+        });
+        histogram
+    }
+
+    /// Average position of all points, or `None` if the tree is empty.
+    pub fn centroid(&self) -> Option<Vec2> {
+        let mut sum = Vec2::ZERO;
+        let mut count = 0;
+        for point in self.iter() {
+            sum += point.position;
+            count += 1;
+        }
+        (count > 0).then(|| sum / count as f32)
+    }
+
+    /// Number of points in each top level quadrant, indexed in `Quadrant` order (NW, NE, SW, SE).
+    /// An unsplit root is divided at its midlines with the rule used when splitting.
+    pub fn quadrant_counts(&self) -> [usize; 4] {
+        let quad = (self.left_x, self.bottom_y, self.width, self.height);
+        match &self.root {
+            Node::Leaf{ value } => {
+                let mut counts = [0; 4];
+                for point in value {
+                    counts[Quadrant::of(quad, point.position) as usize] += 1;
+                }
+                counts
+            },
+            Node::Branch{ nw, ne, sw, se } => [nw, ne, sw, se].map(|child| child.count_up_to(usize::MAX)),
         }
     }
 
-    fn insert(&mut self, ball: Point, points_per_quad: usize) {
-        let mut node = &mut self.root;
-        let mut width = self.width;
-        let mut height = self.height;
-        let mut x = self.left_x;
-        let mut y = self.bottom_y;
+    fn visit_leaves<'a>(&'a self, f: impl FnMut(&'a [Point])) {
+        self.visit_rect(self.left_x, self.bottom_y, self.left_x + self.width, self.bottom_y + self.height, f);
+    }
+
+    /// Returns the points sharing the leaf that contains `(x, y)`, except the point with id `id`.
+    pub fn leaf_neighbors(&self, id: usize, x: f32, y: f32) -> Vec<&Point> {
+        let position = Vec2::new(x, y);
+        let mut node = &self.root;
+        let mut quad = (self.left_x, self.bottom_y, self.width, self.height);
         loop {
             match node {
-                Node::Leaf{ value } => {
-                    value.push(ball);
-                    if value.len() > points_per_quad {
-                        width /= 2.0;
-                        height /= 2.0;
-                        let x_mid = x + width;
-                        let y_mid = y + height;
-                        let (mut north, mut south): (Vec<Point>, Vec<Point>) = value.drain(..).partition(|b| b.position.y >= y_mid);
-                        let (nw, ne): (Vec<Point>, Vec<Point>) = north.drain(..).partition(|b| b.position.x < x_mid);
-                        let (sw, se): (Vec<Point>, Vec<Point>) = south.drain(..).partition(|b| b.position.x < x_mid);
-
-                        *node = Node::Branch{ 
-                            nw: Box::new(Node::Leaf{ value: nw }),
-                            ne: Box::new(Node::Leaf{ value: ne }),
-                            sw: Box::new(Node::Leaf{ value: sw }),
-                            se: Box::new(Node::Leaf{ value: se }),
-                        };
-                    }
-                    return;
-                },
+                Node::Leaf{ value } => return value.iter().filter(|p| p.id != id).collect(),
                 Node::Branch{ nw, ne, sw, se } => {
-                    width /= 2.0;
-                    height /= 2.0;
-                    let x_mid = x + width;
-                    let y_mid = y + height;
-                    if ball.position.x < x_mid {
-                        if ball.position.y > y_mid {
-                            node = nw;
-                            y = y_mid;
-                        } else {
-                            node = sw;
-                        }
-                    } else {
-                        if ball.position.y > y_mid {
-                            node = ne;
-                            x = x_mid;
-                            y = y_mid;
-                        } else {
-                            node = se;
-                            x = x_mid;
-                        }
-                    }
+                    let quadrant = Quadrant::of(quad, position);
+                    quad = quadrant.quad(quad);
+                    node = match quadrant {
+                        Quadrant::NW => nw,
+                        Quadrant::NE => ne,
+                        Quadrant::SW => sw,
+                        Quadrant::SE => se,
+                    };
                 }
             }
         }
+    }
 
+    /// Quadrants chosen on the way from the root to the leaf containing `(x, y)`, empty for an
+    /// unsplit root. The length is the depth of that leaf.
+    pub fn descent_path(&self, x: f32, y: f32) -> Vec<Quadrant> {
+        let position = Vec2::new(x, y);
+        let mut path = Vec::new();
+        let mut node = &self.root;
+        let mut quad = (self.left_x, self.bottom_y, self.width, self.height);
+        while let Node::Branch{ nw, ne, sw, se } = node {
+            let quadrant = Quadrant::of(quad, position);
+            path.push(quadrant);
+            quad = quadrant.quad(quad);
+            node = match quadrant {
+                Quadrant::NW => nw,
+                Quadrant::NE => ne,
+                Quadrant::SW => sw,
+                Quadrant::SE => se,
+            };
+        }
+        path
     }
-    
-    pub fn draw_quad_tree_outlines(&self, draw: &nannou::draw::Draw) {
-        QuadTree::draw_quad_tree_outlines_rec(draw, &self.root, self.left_x, self.bottom_y, self.width, self.height);
+
+    /// Returns up to `k` points with their center within `max_radius` of `(x, y)`, closest first.
+    /// Ties keep the order of `query_radius`.
+    pub fn k_nearest_within(&self, x: f32, y: f32, k: usize, max_radius: f32) -> Vec<&Point> {
+        let center = Vec2::new(x, y);
+        let mut result = self.query_radius_filter(x, y, max_radius, |p| p.position.distance_squared(center) <= max_radius * max_radius);
+        result.sort_by(|a, b| a.position.distance_squared(center).total_cmp(&b.position.distance_squared(center)));
+        result.truncate(k);
+        result
+    }
+
+    /// Closest point within `range` of `(x, y)` in each of the half planes left, right, above and
+    /// below `(x, y)`, in that order. A point off both axes counts for two half planes, a point on
+    /// one of the lines through `(x, y)` for neither side of it.
+    pub fn nearest_per_direction(&self, x: f32, y: f32, range: f32) -> [Option<&Point>; 4] {
+        let center = Vec2::new(x, y);
+        let mut best: [Option<(&Point, f32)>; 4] = [None; 4];
+        self.visit_radius(x, y, range, |leaf| {
+            for point in leaf {
+                let distance = point.position.distance_squared(center);
+                if distance > range * range {
+                    continue;
+                }
+                let offset = point.position - center;
+                let sides = [offset.x < 0.0, offset.x > 0.0, offset.y > 0.0, offset.y < 0.0];
+                for (side, best) in sides.into_iter().zip(&mut best) {
+                    if side && best.is_none_or(|(_, d)| distance < d) {
+                        *best = Some((point, distance));
+                    }
+                }
+            }
+        });
+        best.map(|best| best.map(|(point, _)| point))
+    }
+
+    /// Point with the center closest to `(x, y)`, or `None` if the tree is empty.
+    pub fn nearest(&self, x: f32, y: f32) -> Option<&Point> {
+        self.nearest_excluding(x, y, &[])
+    }
+
+    /// Like `nearest`, but never returns a point whose id is in `exclude`.
+    pub fn nearest_excluding(&self, x: f32, y: f32, exclude: &[usize]) -> Option<&Point> {
+        let mut best = None;
+        let mut best_distance = f32::INFINITY;
+        QuadTree::nearest_rec(&self.root, (self.left_x, self.bottom_y, self.width, self.height), Vec2::new(x, y), exclude, &mut best, &mut best_distance);
+        best
     }
 
-    fn draw_quad_tree_outlines_rec(draw: &nannou::draw::Draw, node: &Node, x: f32, y: f32, width: f32, height: f32) {
+    /// Descends into the children closest to `position` first and skips quads that can't contain
+    /// anything closer than the best point found so far. Distances are squared.
+    fn nearest_rec<'a>(node: &'a Node, quad: Quad, position: Vec2, exclude: &[usize], best: &mut Option<&'a Point>, best_distance: &mut f32) {
+        let (x, y, width, height) = quad;
+        let closest = position.clamp(Vec2::new(x, y), Vec2::new(x + width, y + height));
+        if closest.distance_squared(position) > *best_distance {
+            return;
+        }
         match node {
-            Node::Leaf{ .. } => {
-                draw.rect()
-                    .x_y(x + width / 2.0, y + height / 2.0)
-                    .w_h(width, height)
-                    .stroke(WHITE)
-                    .stroke_weight(1.0)
-                    .z(50.0)
-                    .no_fill();
+            Node::Leaf{ value } => {
+                for point in value.iter().filter(|p| !exclude.contains(&p.id)) {
+                    let distance = point.position.distance_squared(position);
+                    if distance < *best_distance {
+                        *best = Some(point);
+                        *best_distance = distance;
+                    }
+                }
             },
             Node::Branch{ nw, ne, sw, se } => {
-                let x_mid = x + width / 2.0;
-                let y_mid = y + height / 2.0;
-                QuadTree::draw_quad_tree_outlines_rec(draw, nw, x, y_mid, width / 2.0, height / 2.0);
-                QuadTree::draw_quad_tree_outlines_rec(draw, ne, x_mid, y_mid, width / 2.0, height / 2.0);
-                QuadTree::draw_quad_tree_outlines_rec(draw, sw, x, y, width / 2.0, height / 2.0);
-                QuadTree::draw_quad_tree_outlines_rec(draw, se, x_mid, y, width / 2.0, height / 2.0);
+                let mut children = [(nw, Quadrant::NW), (ne, Quadrant::NE), (sw, Quadrant::SW), (se, Quadrant::SE)].map(|(child, quadrant)| {
+                    let quad = quadrant.quad(quad);
+                    let center = Vec2::new(quad.0 + quad.2 / 2.0, quad.1 + quad.3 / 2.0);
+                    (center.distance_squared(position), child, quad)
+                });
+                children.sort_by(|a, b| a.0.total_cmp(&b.0));
+                for (_, child, quad) in children {
+                    QuadTree::nearest_rec(child, quad, position, exclude, best, best_distance);
+                }
             }
         }
     }
-}
 
-#[derive(Debug)]
-enum Node {
-    Leaf{
-        value: Vec<Point>,
-    },
-    Branch{
-        nw: Box<Node>,
-        ne: Box<Node>,
-        sw: Box<Node>,
-        se: Box<Node>,
+    /// Returns the point farthest from `(x, y)`, or `None` if the tree is empty.
+    pub fn farthest(&self, x: f32, y: f32) -> Option<&Point> {
+        let mut best = None;
+        let mut best_distance = -1.0;
+        QuadTree::farthest_rec(&self.root, (self.left_x, self.bottom_y, self.width, self.height), Vec2::new(x, y), &mut best, &mut best_distance);
+        best
+    }
+
+    /// Mirror image of `nearest_rec`: descends into the children farthest from `position` first
+    /// and skips quads whose farthest corner is closer than the best point found so far.
+    fn farthest_rec<'a>(node: &'a Node, quad: Quad, position: Vec2, best: &mut Option<&'a Point>, best_distance: &mut f32) {
+        let (x, y, width, height) = quad;
+        let farthest = Vec2::new((position.x - x).abs().max((position.x - x - width).abs()), (position.y - y).abs().max((position.y - y - height).abs()));
+        if farthest.length_squared() < *best_distance {
+            return;
+        }
+        match node {
+            Node::Leaf{ value } => {
+                for point in value {
+                    let distance = point.position.distance_squared(position);
+                    if distance > *best_distance {
+                        *best = Some(point);
+                        *best_distance = distance;
+                    }
+                }
+            },
+            Node::Branch{ nw, ne, sw, se } => {
+                let mut children = [(nw, Quadrant::NW), (ne, Quadrant::NE), (sw, Quadrant::SW), (se, Quadrant::SE)].map(|(child, quadrant)| {
+                    let quad = quadrant.quad(quad);
+                    let center = Vec2::new(quad.0 + quad.2 / 2.0, quad.1 + quad.3 / 2.0);
+                    (center.distance_squared(position), child, quad)
+                });
+                children.sort_by(|a, b| b.0.total_cmp(&a.0));
+                for (_, child, quad) in children {
+                    QuadTree::farthest_rec(child, quad, position, best, best_distance);
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    /// Returns the points whose center is farther than `radius` from `(x, y)`. Quads lying entirely
+    /// inside the circle are skipped without looking at their points.
+    pub fn query_outside_radius(&self, x: f32, y: f32, radius: f32) -> Vec<&Point> {
+        let center = Vec2::new(x, y);
+        let mut result = Vec::new();
+        self.try_visit_points(|quad_x, quad_y, width, height| {
+            let farthest_x = (x - quad_x).abs().max((quad_x + width - x).abs());
+            let farthest_y = (y - quad_y).abs().max((quad_y + height - y).abs());
+            farthest_x * farthest_x + farthest_y * farthest_y > radius * radius
+        }, |p| {
+            if p.position.distance_squared(center) > radius * radius {
+                result.push(p);
+            }
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    /// Replays the broad phase of `query_radius` for debugging. Returns the quads of the leaves it
+    /// visits and the quads it prunes without descending, which together tile the tree bounds.
+    pub fn trace_radius(&self, x: f32, y: f32, radius: f32) -> (Vec<Quad>, Vec<Quad>) {
+        let rect = (x - radius, y - radius, x + radius, y + radius);
+        let mut visited = Vec::new();
+        let mut pruned = Vec::new();
+        QuadTree::trace_rec(&self.root, (self.left_x, self.bottom_y, self.width, self.height), rect, &mut visited, &mut pruned);
+        (visited, pruned)
+    }
+
+    fn trace_rec(node: &Node, quad: Quad, rect: (f32, f32, f32, f32), visited: &mut Vec<Quad>, pruned: &mut Vec<Quad>) {
+        let (x, y, width, height) = quad;
+        if !rect_overlaps(rect, x, y, width, height) {
+            pruned.push(quad);
+            return;
+        }
+        match node {
+            Node::Leaf{ .. } => visited.push(quad),
+            Node::Branch{ nw, ne, sw, se } => {
+                QuadTree::trace_rec(nw, Quadrant::NW.quad(quad), rect, visited, pruned);
+                QuadTree::trace_rec(ne, Quadrant::NE.quad(quad), rect, visited, pruned);
+                QuadTree::trace_rec(sw, Quadrant::SW.quad(quad), rect, visited, pruned);
+                QuadTree::trace_rec(se, Quadrant::SE.quad(quad), rect, visited, pruned);
+            }
+        }
+    }
+
+    /// Calls `f` with the points and the `(x, y, width, height)` bounds of every leaf overlapping the
+    /// rectangle with bottom left corner `(x, y)`, in `iter` order.
+    pub fn leaves_in_rect<F: FnMut(&[Point], f32, f32, f32, f32)>(&self, x: f32, y: f32, width: f32, height: f32, mut f: F) {
+        let rect = (x, y, x + width, y + height);
+        QuadTree::leaves_in_rect_rec(&self.root, (self.left_x, self.bottom_y, self.width, self.height), rect, &mut f);
+    }
+
+    /// Returns the `(x, y, width, height)` bounds of the leaf with the fewest points per area among
+    /// the leaves within `search_radius` of `(x, y)`, preferring the larger leaf on a tie, or `None`
+    /// if no leaf is in reach.
+    pub fn sparsest_leaf_near(&self, x: f32, y: f32, search_radius: f32) -> Option<Quad> {
+        let center = Vec2::new(x, y);
+        let mut sparsest: Option<(f32, Quad)> = None;
+        self.leaves_in_rect(x - search_radius, y - search_radius, search_radius * 2.0, search_radius * 2.0, |leaf, quad_x, quad_y, width, height| {
+            let nearest = center.clamp(Vec2::new(quad_x, quad_y), Vec2::new(quad_x + width, quad_y + height));
+            if nearest.distance_squared(center) > search_radius * search_radius {
+                return;
+            }
+            let density = leaf.len() as f32 / (width * height);
+            if sparsest.is_none_or(|(best, (_, _, best_width, best_height))| density < best || density == best && width * height > best_width * best_height) {
+                sparsest = Some((density, (quad_x, quad_y, width, height)));
+            }
+        });
+        sparsest.map(|(_, quad)| quad)
+    }
+
+    fn leaves_in_rect_rec(node: &Node, quad: Quad, rect: (f32, f32, f32, f32), f: &mut impl FnMut(&[Point], f32, f32, f32, f32)) {
+        let (x, y, width, height) = quad;
+        if !rect_overlaps(rect, x, y, width, height) {
+            return;
+        }
+        match node {
+            Node::Leaf{ value } => f(value, x, y, width, height),
+            Node::Branch{ nw, ne, sw, se } => {
+                QuadTree::leaves_in_rect_rec(nw, Quadrant::NW.quad(quad), rect, f);
+                QuadTree::leaves_in_rect_rec(ne, Quadrant::NE.quad(quad), rect, f);
+                QuadTree::leaves_in_rect_rec(sw, Quadrant::SW.quad(quad), rect, f);
+                QuadTree::leaves_in_rect_rec(se, Quadrant::SE.quad(quad), rect, f);
+            }
+        }
+    }
+
+    fn visit_radius<'a>(&'a self, x: f32, y: f32, radius: f32, f: impl FnMut(&'a [Point])) {
+        self.visit_rect(x - radius, y - radius, x + radius, y + radius, f);
+    }
+
+    fn visit_rect<'a>(&'a self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, mut f: impl FnMut(&'a [Point])) {
+        self.try_visit_rect(min_x, min_y, max_x, max_y, |leaf| {
+            f(leaf);
+            ControlFlow::Continue(())
+        });
+    }
+
+    fn visit_radius_points<'a>(&'a self, x: f32, y: f32, radius: f32, f: impl FnMut(&'a Point)) {
+        self.visit_rect_points(x - radius, y - radius, x + radius, y + radius, f);
+    }
+
+    fn visit_rect_points<'a>(&'a self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, mut f: impl FnMut(&'a Point)) {
+        self.try_visit_points(|quad_x, quad_y, width, height| rect_overlaps((min_x, min_y, max_x, max_y), quad_x, quad_y, width, height), |p| {
+            f(p);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Like `try_visit`, but calls `f` per point. A loose tree passes each point once, at the first
+    /// leaf holding a copy of it.
+    fn try_visit_points<'a>(&'a self, overlaps: impl Fn(f32, f32, f32, f32) -> bool, mut f: impl FnMut(&'a Point) -> ControlFlow<()>) {
+        let mut seen = self.loose.then(HashSet::new);
+        self.try_visit(overlaps, |leaf| {
+            for p in leaf {
+                if seen.as_mut().is_none_or(|seen| seen.insert(p.id)) {
+                    f(p)?;
+                }
+            }
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Visits the leaves overlapping the rectangle until `f` breaks.
+    fn try_visit_rect<'a>(&'a self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, f: impl FnMut(&'a [Point]) -> ControlFlow<()>) {
+        self.try_visit(|quad_x, quad_y, width, height| rect_overlaps((min_x, min_y, max_x, max_y), quad_x, quad_y, width, height), f);
+    }
+
+    /// Visits the leaves whose quads pass `overlaps(x, y, width, height)` until `f` breaks.
+    /// Branches failing `overlaps` are pruned with all their children.
+    fn try_visit<'a>(&'a self, overlaps: impl Fn(f32, f32, f32, f32) -> bool, mut f: impl FnMut(&'a [Point]) -> ControlFlow<()>) {
+        let _ = QuadTree::try_visit_rec(&self.root, self.left_x, self.bottom_y, self.width, self.height, &overlaps, &mut f);
+    }
+
+    // Measured against the explicit-stack `visit_rect_iterative` on 200k points with 50k radius
+    // queries: the recursion took 88-103 ms versus 122-138 ms on uniform data and 704-732 ms versus
+    // 889-900 ms on clustered data, as it does not allocate a stack per query. Rerun with
+    // `cargo test --release -- --ignored bench_traversals --nocapture`.
+    fn try_visit_rec<'a, T>(node: &'a Node<T>, quad_x: f32, quad_y: f32, width: f32, height: f32, overlaps: &impl Fn(f32, f32, f32, f32) -> bool, f: &mut impl FnMut(&'a [T]) -> ControlFlow<()>) -> ControlFlow<()> {
+        if !overlaps(quad_x, quad_y, width, height) {
+            return ControlFlow::Continue(());
+        }
+        match node {
+            Node::Leaf{ value } => f(value),
+            Node::Branch{ nw, ne, sw, se } => {
+                let width = width / 2.0;
+                let height: f32 = height / 2.0;
+                let x_mid = quad_x + width;
+                let y_mid = quad_y + height;
+                QuadTree::try_visit_rec(nw, quad_x, y_mid, width, height, overlaps, f)?;
+                QuadTree::try_visit_rec(ne, x_mid, y_mid, width, height, overlaps, f)?;
+                QuadTree::try_visit_rec(sw, quad_x, quad_y, width, height, overlaps, f)?;
+                QuadTree::try_visit_rec(se, x_mid, quad_y, width, height, overlaps, f)
+            }
+        }
+    }
+
+    /// The explicit-stack traversal `try_visit_rec` replaced, kept to benchmark against.
+    #[cfg(test)]
+    fn visit_rect_iterative<'a>(&'a self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, mut f: impl FnMut(&'a [Point])) {
+        let mut stack = vec![(&self.root, self.left_x, self.bottom_y, self.width, self.height)];
+        while let Some((node, quad_x, quad_y, width, height)) = stack.pop() {
+            if max_x < quad_x || min_x > quad_x + width || max_y < quad_y || min_y > quad_y + height {
+                continue;
+            }
+            match node {
+                Node::Leaf{ value } => f(value),
+                Node::Branch{ nw, ne, sw, se } => {
+                    let width = width / 2.0;
+                    let height: f32 = height / 2.0;
+                    let x_mid = quad_x + width;
+                    let y_mid = quad_y + height;
+                    stack.push((nw, quad_x, y_mid, width, height));
+                    stack.push((ne, x_mid, y_mid, width, height));
+                    stack.push((sw, quad_x, quad_y, width, height));
+                    stack.push((se, x_mid, quad_y, width, height));
+                }
+            }
+        }
+    }
+
+    /// Inserts a point, splitting its leaf once it holds more than `points_per_quad` points.
+    /// The point is expected to lie within the tree bounds. Points with a NaN or infinite position
+    /// are skipped, as they can't be routed consistently, and `false` is returned.
+    pub fn insert(&mut self, ball: Point, points_per_quad: usize) -> bool {
+        if !ball.position.is_finite() {
+            return false;
+        }
+        QuadTree::insert_rec(&mut self.root, ball, (self.left_x, self.bottom_y, self.width, self.height), points_per_quad, self.max_depth, self.expected_points, &|p: &Point| p.position);
+        true
+    }
+
+    /// Like `insert`, but if another point sits at exactly the same position, first moves the point
+    /// by up to `jitter` along each axis, staying within the bounds and keeping its velocity. This
+    /// keeps coincident points from subdividing down to the depth limit. A `jitter` of 0 never moves it.
+    pub fn insert_jittered(&mut self, mut ball: Point, points_per_quad: usize, jitter: f32, rng: &mut impl Rng) {
+        if jitter > 0.0 && self.any_within(ball.position.x, ball.position.y, 0.0, None) {
+            let offset = Vec2::new(rng.gen_range(-jitter..=jitter), rng.gen_range(-jitter..=jitter));
+            let min = Vec2::new(self.left_x, self.bottom_y);
+            let position = (ball.position + offset).clamp(min, min + Vec2::new(self.width, self.height));
+            ball.prev_position += position - ball.position;
+            ball.position = position;
+        }
+        self.insert(ball, points_per_quad);
+    }
+
+    /// Buckets the point ids into a grid of square cells of side `cell_size` covering the tree bounds.
+    /// Points are visited leaf by leaf, so each cell lists its ids in `iter` order. The cell size is
+    /// raised until the grid has at most `MAX_GRID_CELLS` cells.
+    ///
+    /// # Panics
+    /// If a point id or the number of points doesn't fit in a `u32`, the GPU side index type.
+    pub fn to_uniform_grid(&self, cell_size: f32) -> UniformGridData {
+        let dimensions = |cell_size: f32| {
+            ((self.width / cell_size).ceil().max(1.0) as usize, (self.height / cell_size).ceil().max(1.0) as usize)
+        };
+        let mut cell_size = QuadTree::clamp_size(cell_size).max((self.width * self.height / MAX_GRID_CELLS as f32).sqrt());
+        // Rounding the cell counts up can still overshoot the limit by a row and a column.
+        while dimensions(cell_size).0.saturating_mul(dimensions(cell_size).1) > MAX_GRID_CELLS {
+            cell_size *= 1.01;
+        }
+        let (columns, rows) = dimensions(cell_size);
+        let mut grid = UniformGridData {
+            origin: Vec2::new(self.left_x, self.bottom_y),
+            cell_size,
+            columns,
+            rows,
+            cell_starts: vec![0; columns * rows + 1],
+            ids: Vec::new(),
+        };
+        // Counting sort: count per cell, turn the counts into start offsets, then scatter the ids.
+        let cells: Vec<(usize, u32)> = self.iter().map(|p| (grid.cell_of(p.position), u32::try_from(p.id).expect("point id exceeds u32::MAX"))).collect();
+        assert!(u32::try_from(cells.len()).is_ok(), "more than u32::MAX points");
+        for &(cell, _) in &cells {
+            grid.cell_starts[cell + 1] += 1;
+        }
+        for i in 1..grid.cell_starts.len() {
+            grid.cell_starts[i] += grid.cell_starts[i - 1];
+        }
+        let mut next = grid.cell_starts.clone();
+        grid.ids = vec![0; cells.len()];
+        for (cell, id) in cells {
+            grid.ids[next[cell] as usize] = id;
+            next[cell] += 1;
+        }
+        grid
+    }
+
+    /// Like `insert`, but first doubles the bounds towards the point until they contain it. Returns
+    /// the point back without inserting it if its position is not finite or so far out that the
+    /// bounds would overflow before reaching it, the tree is left untouched then.
+    pub fn insert_growing(&mut self, ball: Point, points_per_quad: usize) -> Option<Point> {
+        if !ball.position.is_finite() {
+            return Some(ball);
+        }
+        // Growing a leaf only moves the bounds, so a throwaway empty tree tells in advance whether
+        // the doubling stays finite.
+        let mut probe = QuadTree::new(self.left_x, self.bottom_y, self.width, self.height);
+        while !probe.contains(ball.position) {
+            probe.grow_towards(ball.position);
+            if !probe.has_finite_bounds() {
+                return Some(ball);
+            }
+        }
+        while !self.contains(ball.position) {
+            self.grow_towards(ball.position);
+        }
+        self.insert(ball, points_per_quad);
+        None
+    }
+
+    /// Doubles the bounds towards `position`. A branch root becomes the quadrant of the new root
+    /// facing away from `position`, a leaf root is kept as is.
+    fn grow_towards(&mut self, position: Vec2) {
+        let west = position.x < self.left_x;
+        let south = position.y < self.bottom_y;
+        if west {
+            self.left_x -= self.width;
+        }
+        if south {
+            self.bottom_y -= self.height;
+        }
+        self.width *= 2.0;
+        self.height *= 2.0;
+        if let Node::Leaf{ .. } = self.root {
+            return;
+        }
+        let old_root = Box::new(std::mem::replace(&mut self.root, Node::Leaf{ value: Vec::new() }));
+        let empty = || Box::new(Node::Leaf{ value: Vec::new() });
+        self.root = match (west, south) {
+            (true, true) => Node::Branch{ nw: empty(), ne: old_root, sw: empty(), se: empty() },
+            (true, false) => Node::Branch{ nw: empty(), ne: empty(), sw: empty(), se: old_root },
+            (false, true) => Node::Branch{ nw: old_root, ne: empty(), sw: empty(), se: empty() },
+            (false, false) => Node::Branch{ nw: empty(), ne: empty(), sw: old_root, se: empty() },
+        };
+    }
+
+    /// Inserts `ball` below `node` covering `quad` as `(x, y, width, height)`, locating items through
+    /// `position`. Leaves at `max_depth` below `node` don't split. Leaves reserve their share of
+    /// `expected_points` below `node`, see `QuadTree::expected_points`.
+    fn insert_rec<T>(node: &mut Node<T>, ball: T, quad: Quad, points_per_quad: usize, max_depth: usize, expected_points: usize, position: &impl Fn(&T) -> Vec2) {
+        let mut node = node;
+        let (mut x, mut y, mut width, mut height) = quad;
+        let ball_position = position(&ball);
+        let mut depth = 0;
+        let share = |depth: usize| expected_points.checked_shr(2 * depth as u32).unwrap_or(0).min(points_per_quad + 1);
+        loop {
+            match node {
+                Node::Leaf{ value } => {
+                    // A no-op once the leaf holds its share, so only leaves below it reserve.
+                    value.reserve(share(depth).saturating_sub(value.len()));
+                    value.push(ball);
+                    if value.len() > points_per_quad && depth < max_depth {
+                        node.split((x, y, width, height), share(depth + 1), position);
+                    }
+                    return;
+                },
+                Node::Branch{ nw, ne, sw, se } => {
+                    let quadrant = Quadrant::of((x, y, width, height), ball_position);
+                    (x, y, width, height) = quadrant.quad((x, y, width, height));
+                    depth += 1;
+                    node = match quadrant {
+                        Quadrant::NW => nw,
+                        Quadrant::NE => ne,
+                        Quadrant::SW => sw,
+                        Quadrant::SE => se,
+                    };
+                }
+            }
+        }
+
+    }
+    
+    /// Inserts a batch of points, with the same contract as `insert`. The batch is sorted by Morton
+    /// code first so consecutive inserts descend into nearby leaves. The resulting point set equals
+    /// inserting one by one, though leaf order follows the sorted order. Returns the number of
+    /// points skipped for a non-finite position.
+    pub fn extend<I: IntoIterator<Item = Point>>(&mut self, points: I, points_per_quad: usize) -> usize {
+        let mut points: Vec<Point> = points.into_iter().collect();
+        points.sort_by_cached_key(|p| self.morton_code(p.position));
+        let mut skipped = 0;
+        for point in points {
+            if !self.insert(point, points_per_quad) {
+                skipped += 1;
+            }
+        }
+        skipped
+    }
+
+    /// Z-order code of `position`, quantized to 16 bits per axis over the tree bounds.
+    fn morton_code(&self, position: Vec2) -> u32 {
+        let quantize = |value: f32, start: f32, size: f32| (((value - start) / size).clamp(0.0, 1.0) * u16::MAX as f32) as u32;
+        let x = quantize(position.x, self.left_x, self.width);
+        let y = quantize(position.y, self.bottom_y, self.height);
+        spread_bits(x) | (spread_bits(y) << 1)
+    }
+
+    /// Restructures the tree in place for a new capacity: leaves above `points_per_quad` split
+    /// (down to the depth limit) and branches below `points_per_quad / MERGE_DIVISOR` merge, the same
+    /// thresholds `insert` and `remove` use.
+    pub fn set_capacity(&mut self, points_per_quad: usize) {
+        let quad = (self.left_x, self.bottom_y, self.width, self.height);
+        QuadTree::rebalance(&mut self.root, quad, points_per_quad, 0, self.max_depth);
+    }
+
+    fn rebalance(node: &mut Node, quad: Quad, points_per_quad: usize, depth: usize, max_depth: usize) {
+        let merge_below = points_per_quad / MERGE_DIVISOR;
+        match node {
+            Node::Leaf{ value } => {
+                if value.len() > points_per_quad && depth < max_depth {
+                    node.split(quad, 0, &|p: &Point| p.position);
+                    QuadTree::rebalance(node, quad, points_per_quad, depth, max_depth);
+                }
+            },
+            Node::Branch{ .. } if node.count_up_to(merge_below) < merge_below => node.collapse(),
+            Node::Branch{ nw, ne, sw, se } => {
+                QuadTree::rebalance(nw, Quadrant::NW.quad(quad), points_per_quad, depth + 1, max_depth);
+                QuadTree::rebalance(ne, Quadrant::NE.quad(quad), points_per_quad, depth + 1, max_depth);
+                QuadTree::rebalance(sw, Quadrant::SW.quad(quad), points_per_quad, depth + 1, max_depth);
+                QuadTree::rebalance(se, Quadrant::SE.quad(quad), points_per_quad, depth + 1, max_depth);
+            }
+        }
+    }
+
+    /// Removes the point with id `id` stored at `(x, y)` and returns it.
+    ///
+    /// Branches only merge back into a leaf once their subtree holds fewer than
+    /// `points_per_quad / MERGE_DIVISOR` points, while leaves split above `points_per_quad`. The gap
+    /// keeps a leaf hovering around the capacity from splitting and merging on every change. Merging
+    /// concatenates the children in NW, NE, SW, SE order.
+    pub fn remove(&mut self, id: usize, x: f32, y: f32, points_per_quad: usize) -> Option<Point> {
+        let quad = (self.left_x, self.bottom_y, self.width, self.height);
+        QuadTree::remove_rec(&mut self.root, id, Vec2::new(x, y), quad, points_per_quad / MERGE_DIVISOR)
+    }
+
+    fn remove_rec(node: &mut Node, id: usize, position: Vec2, quad: Quad, merge_below: usize) -> Option<Point> {
+        let removed = match node {
+            Node::Leaf{ value } => {
+                let index = value.iter().position(|p| p.id == id)?;
+                return Some(value.remove(index));
+            },
+            Node::Branch{ nw, ne, sw, se } => {
+                let quadrant = Quadrant::of(quad, position);
+                let child = match quadrant {
+                    Quadrant::NW => nw,
+                    Quadrant::NE => ne,
+                    Quadrant::SW => sw,
+                    Quadrant::SE => se,
+                };
+                QuadTree::remove_rec(child, id, position, quadrant.quad(quad), merge_below)?
+            }
+        };
+        if node.count_up_to(merge_below) < merge_below {
+            node.collapse();
+        }
+        Some(removed)
+    }
+
+    /// Draws the outline of every leaf, colored by depth with a hue rotation when `graded` is set and
+    /// plain white otherwise. With `skip_empty` leaves without points are left out.
+    pub fn draw_quad_tree_outlines(&self, draw: &nannou::draw::Draw, graded: bool, skip_empty: bool) {
+        let depth = if graded { Some(0) } else { None };
+        QuadTree::draw_quad_tree_outlines_rec(draw, &self.root, (self.left_x, self.bottom_y, self.width, self.height), depth, skip_empty);
+    }
+
+    fn draw_quad_tree_outlines_rec<T>(draw: &nannou::draw::Draw, node: &Node<T>, quad: Quad, depth: Option<usize>, skip_empty: bool) {
+        match node {
+            Node::Leaf{ value } => {
+                if skip_empty && value.is_empty() {
+                    return;
+                }
+                let (x, y, width, height) = quad;
+                let color: Srgb = match depth {
+                    Some(depth) => hsv((depth as f32 * 0.15).fract(), 0.8, 1.0).into(),
+                    None => WHITE.into_format(),
+                };
+                draw.rect()
+                    .x_y(x + width / 2.0, y + height / 2.0)
+                    .w_h(width, height)
+                    .stroke(color)
+                    .stroke_weight(1.0)
+                    .z(50.0)
+                    .no_fill();
+            },
+            Node::Branch{ nw, ne, sw, se } => {
+                let depth = depth.map(|d| d + 1);
+                QuadTree::draw_quad_tree_outlines_rec(draw, nw, Quadrant::NW.quad(quad), depth, skip_empty);
+                QuadTree::draw_quad_tree_outlines_rec(draw, ne, Quadrant::NE.quad(quad), depth, skip_empty);
+                QuadTree::draw_quad_tree_outlines_rec(draw, sw, Quadrant::SW.quad(quad), depth, skip_empty);
+                QuadTree::draw_quad_tree_outlines_rec(draw, se, Quadrant::SE.quad(quad), depth, skip_empty);
+            }
+        }
+    }
+}
+
+/// A quad as `(x, y, width, height)` with `(x, y)` its bottom left corner.
+pub type Quad = (f32, f32, f32, f32);
+
+/// Whether `rect`, given as `(min_x, min_y, max_x, max_y)`, overlaps the quad.
+fn rect_overlaps(rect: (f32, f32, f32, f32), quad_x: f32, quad_y: f32, width: f32, height: f32) -> bool {
+    let (min_x, min_y, max_x, max_y) = rect;
+    !(max_x < quad_x || min_x > quad_x + width || max_y < quad_y || min_y > quad_y + height)
+}
+
+/// Slab test of the segment from `start` to `end` against `(min_x, min_y, max_x, max_y)`.
+fn segment_overlaps_rect(start: Vec2, end: Vec2, rect: (f32, f32, f32, f32)) -> bool {
+    let (min_x, min_y, max_x, max_y) = rect;
+    let direction = end - start;
+    let (mut t_min, mut t_max) = (0.0f32, 1.0f32);
+    for (origin, delta, min, max) in [(start.x, direction.x, min_x, max_x), (start.y, direction.y, min_y, max_y)] {
+        if delta == 0.0 {
+            if origin < min || origin > max {
+                return false;
+            }
+        } else {
+            let (t0, t1) = ((min - origin) / delta, (max - origin) / delta);
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+    }
+    t_min <= t_max
+}
+
+/// Spreads the lower 16 bits of `v` to the even bit positions.
+fn spread_bits(v: u32) -> u32 {
+    let mut v = v & 0xFFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333;
+    (v | (v << 1)) & 0x5555_5555
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quadrant {
+    NW,
+    NE,
+    SW,
+    SE,
+}
+
+impl Quadrant {
+    /// Quadrant of `quad`, given as `(x, y, width, height)`, that `position` belongs to. Points on the
+    /// vertical midline belong to the east and points on the horizontal midline to the north half,
+    /// the same rule a leaf uses when it splits.
+    fn of(quad: Quad, position: Vec2) -> Quadrant {
+        let (x, y, width, height) = quad;
+        let east = position.x >= x + width / 2.0;
+        let north = position.y >= y + height / 2.0;
+        match (north, east) {
+            (true, false) => Quadrant::NW,
+            (true, true) => Quadrant::NE,
+            (false, false) => Quadrant::SW,
+            (false, true) => Quadrant::SE,
+        }
+    }
+
+    /// The part of `quad` covered by this quadrant.
+    fn quad(self, quad: Quad) -> Quad {
+        let (x, y, width, height) = quad;
+        let (width, height) = (width / 2.0, height / 2.0);
+        match self {
+            Quadrant::NW => (x, y + height, width, height),
+            Quadrant::NE => (x + width, y + height, width, height),
+            Quadrant::SW => (x, y, width, height),
+            Quadrant::SE => (x + width, y, width, height),
+        }
+    }
+}
+
+/// Shape of a tree, see `QuadTree::stats`. The root is at depth 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeStats {
+    pub points: usize,
+    pub leaves: usize,
+    pub max_depth: usize,
+}
+
+/// Point ids bucketed into a uniform grid, laid out flat for uploading to the GPU. Cells are numbered
+/// row by row from the bottom left, cell `i` holds `ids[cell_starts[i]..cell_starts[i + 1]]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UniformGridData {
+    /// Bottom left corner of cell 0.
+    pub origin: Vec2,
+    pub cell_size: f32,
+    pub columns: usize,
+    pub rows: usize,
+    pub cell_starts: Vec<u32>,
+    pub ids: Vec<u32>,
+}
+
+impl UniformGridData {
+    /// Cell containing `position`, clamped to the grid.
+    pub fn cell_of(&self, position: Vec2) -> usize {
+        let local = (position - self.origin) / self.cell_size;
+        let column = (local.x.max(0.0) as usize).min(self.columns - 1);
+        let row = (local.y.max(0.0) as usize).min(self.rows - 1);
+        row * self.columns + column
+    }
+
+    pub fn cell(&self, cell: usize) -> &[u32] {
+        &self.ids[self.cell_starts[cell] as usize..self.cell_starts[cell + 1] as usize]
+    }
+
+    /// Ids in the cell of `(x, y)` and its eight neighbors. For a radius of at most `cell_size` these
+    /// include every point within the radius, the lookup a shader would do.
+    pub fn neighbor_candidates(&self, x: f32, y: f32) -> Vec<u32> {
+        let cell = self.cell_of(Vec2::new(x, y));
+        let (column, row) = (cell % self.columns, cell / self.columns);
+        let mut result = Vec::new();
+        for row in row.saturating_sub(1)..=(row + 1).min(self.rows - 1) {
+            for column in column.saturating_sub(1)..=(column + 1).min(self.columns - 1) {
+                result.extend_from_slice(self.cell(row * self.columns + column));
+            }
+        }
+        result
+    }
+}
+
+/// Quadtree over indices into an external point slice, so the authoritative points stay in one
+/// place instead of being copied into the tree. Methods taking `points` expect the slice the tree
+/// was built from.
+#[derive(Debug)]
+pub struct IndexQuadTree {
+    left_x: f32,
+    bottom_y: f32,
+    width: f32,
+    height: f32,
+    root: Node<usize>,
+    /// Run the pair searches on the rayon pool. Both modes return the same pairs in the same order.
+    parallel: bool,
+}
+
+impl IndexQuadTree {
+    pub fn from_points(points: &[Point], left_x: f32, bottom_y: f32, width: f32, height: f32, points_per_quad: usize) -> IndexQuadTree {
+        let mut tree = IndexQuadTree {
+            left_x,
+            bottom_y,
+            width: QuadTree::clamp_size(width),
+            height: QuadTree::clamp_size(height),
+            root: Node::Leaf{ value: Vec::new() },
+            parallel: true,
+        };
+        let quad = (tree.left_x, tree.bottom_y, tree.width, tree.height);
+        for (index, point) in points.iter().enumerate() {
+            let position = point.position;
+            if position.x >= left_x && position.x <= left_x + tree.width && position.y >= bottom_y && position.y <= bottom_y + tree.height {
+                QuadTree::insert_rec(&mut tree.root, index, quad, points_per_quad, MAX_DEPTH, 0, &|&i: &usize| points[i].position);
+            }
+        }
+        tree
+    }
+
+    /// Bounds of the tree as `(x, y, width, height)`, like `QuadTree::bounds`.
+    pub fn bounds(&self) -> Quad {
+        (self.left_x, self.bottom_y, self.width, self.height)
+    }
+
+    /// Returns the indices in all leaves overlapping the circle, like `QuadTree::query_radius`.
+    pub fn query_radius(&self, x: f32, y: f32, radius: f32) -> Vec<usize> {
+        let mut result = Vec::new();
+        let rect = (x - radius, y - radius, x + radius, y + radius);
+        let _ = QuadTree::try_visit_rec(&self.root, self.left_x, self.bottom_y, self.width, self.height, &|quad_x, quad_y, width, height| rect_overlaps(rect, quad_x, quad_y, width, height), &mut |leaf: &[usize]| {
+            result.extend_from_slice(leaf);
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    /// Index version of `QuadTree::any_within`, with `points` the slice the tree was built from.
+    pub fn any_within(&self, points: &[Point], x: f32, y: f32, radius: f32, exclude: Option<usize>) -> bool {
+        let center = Vec2::new(x, y);
+        let rect = (x - radius, y - radius, x + radius, y + radius);
+        QuadTree::try_visit_rec(&self.root, self.left_x, self.bottom_y, self.width, self.height, &|quad_x, quad_y, width, height| rect_overlaps(rect, quad_x, quad_y, width, height), &mut |leaf: &[usize]| {
+            if leaf.iter().any(|&i| Some(i) != exclude && points[i].position.distance_squared(center) <= radius * radius) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }).is_break()
+    }
+
+    /// Returns the indices in all leaves touched by the segment from `start` to `end` grown by
+    /// `radius` to each side, the candidates for a circle of that radius sweeping along it.
+    pub fn query_segment(&self, start: Vec2, end: Vec2, radius: f32) -> Vec<usize> {
+        let mut result = Vec::new();
+        let _ = QuadTree::try_visit_rec(&self.root, self.left_x, self.bottom_y, self.width, self.height, &|quad_x, quad_y, width, height| segment_overlaps_rect(start, end, (quad_x - radius, quad_y - radius, quad_x + width + radius, quad_y + height + radius)), &mut |leaf: &[usize]| {
+            result.extend_from_slice(leaf);
+            ControlFlow::Continue(())
+        });
+        result
+    }
+
+    /// Sequential pair searches are easier to profile and step through in a debugger.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// Index version of `QuadTree::pairs_within`, with the lower index first.
+    pub fn pairs_within(&self, points: &[Point], distance: f32) -> Vec<(usize, usize)> {
+        let pairs_of = |a: usize| {
+            let position = points[a].position;
+            self.query_radius(position.x, position.y, distance)
+                .into_iter()
+                .filter(move |&b| b > a && position.distance_squared(points[b].position) <= distance * distance)
+                .map(move |b| (a, b))
+        };
+        if self.parallel {
+            (0..points.len()).into_par_iter().flat_map_iter(pairs_of).collect()
+        } else {
+            (0..points.len()).flat_map(pairs_of).collect()
+        }
+    }
+
+    /// Returns every unordered pair of indices sharing a leaf or lying in two touching leaves, exactly
+    /// once with the lower index first, without any distance test. Each pair within distance `d` is
+    /// included as long as no leaf is smaller than `d`.
+    pub fn leaf_pairs(&self) -> Vec<(usize, usize)> {
+        let mut leaves = Vec::new();
+        IndexQuadTree::leaves_rec(&self.root, (self.left_x, self.bottom_y, self.width, self.height), &mut leaves);
+        let pairs_of = |&((x, y, width, height), leaf): &(Quad, &[usize])| {
+            // Grow the leaf a little so rounding in the quad subdivision doesn't drop touching leaves.
+            let slack = width.min(height) * 1e-3;
+            let rect = (x - slack, y - slack, x + width + slack, y + height + slack);
+            let mut candidates = Vec::new();
+            let _ = QuadTree::try_visit_rec(&self.root, self.left_x, self.bottom_y, self.width, self.height, &|quad_x, quad_y, width, height| rect_overlaps(rect, quad_x, quad_y, width, height), &mut |other: &[usize]| {
+                candidates.extend_from_slice(other);
+                ControlFlow::Continue(())
+            });
+            let mut pairs = Vec::new();
+            for &a in leaf {
+                pairs.extend(candidates.iter().filter(|&&b| b > a).map(|&b| (a, b)));
+            }
+            pairs
+        };
+        if self.parallel {
+            leaves.par_iter().flat_map_iter(pairs_of).collect()
+        } else {
+            leaves.iter().flat_map(pairs_of).collect()
+        }
+    }
+
+    fn leaves_rec<'a>(node: &'a Node<usize>, quad: Quad, leaves: &mut Vec<(Quad, &'a [usize])>) {
+        match node {
+            Node::Leaf{ value } => leaves.push((quad, value)),
+            Node::Branch{ nw, ne, sw, se } => {
+                IndexQuadTree::leaves_rec(nw, Quadrant::NW.quad(quad), leaves);
+                IndexQuadTree::leaves_rec(ne, Quadrant::NE.quad(quad), leaves);
+                IndexQuadTree::leaves_rec(sw, Quadrant::SW.quad(quad), leaves);
+                IndexQuadTree::leaves_rec(se, Quadrant::SE.quad(quad), leaves);
+            }
+        }
+    }
+
+    /// Shape of the tree like `QuadTree::stats`, with `points` counting the stored indices.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats { points: 0, leaves: 0, max_depth: 0 };
+        self.root.collect_stats(0, &mut stats);
+        stats
+    }
+
+    /// Groups the indices of `points` into clusters of points transitively within `distance` of each
+    /// other. Each cluster is sorted, and clusters are ordered by their lowest index.
+    pub fn connected_components(&self, points: &[Point], distance: f32) -> Vec<Vec<usize>> {
+        // Union-find with path halving, linking the higher root below the lower one.
+        let mut parent: Vec<usize> = (0..points.len()).collect();
+        let find = |parent: &mut Vec<usize>, mut i: usize| {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        };
+        for (a, b) in self.pairs_within(points, distance) {
+            let (root_a, root_b) = (find(&mut parent, a), find(&mut parent, b));
+            parent[root_a.max(root_b)] = root_a.min(root_b);
+        }
+        let mut components: Vec<Vec<usize>> = Vec::new();
+        let mut component_of_root = vec![usize::MAX; points.len()];
+        for i in 0..points.len() {
+            let root = find(&mut parent, i);
+            if component_of_root[root] == usize::MAX {
+                component_of_root[root] = components.len();
+                components.push(Vec::new());
+            }
+            components[component_of_root[root]].push(i);
+        }
+        components
+    }
+
+    /// Returns the overlapping pairs of `points` in `pairs_within` order, without moving anything.
+    pub fn contacts(&self, points: &[Point]) -> Vec<Contact> {
+        let max_radius = points.iter().map(|p| p.radius).fold(0.0, f32::max);
+        self.pairs_within(points, max_radius * 2.0).into_iter().filter_map(|(a, b)| {
+            let axis = points[a].position - points[b].position;
+            let depth = points[a].radius + points[b].radius - axis.length();
+            (depth >= 0.0).then(|| Contact { a, b, normal: axis.normalize_or_zero(), depth })
+        }).collect()
+    }
+}
+
+/// Overlap between the points at indices `a` and `b`. `normal` points from `b` towards `a`, moving
+/// the points apart by `depth` along it resolves the overlap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Contact {
+    pub a: usize,
+    pub b: usize,
+    pub normal: Vec2,
+    pub depth: f32,
+}
+
+#[derive(Debug)]
+enum Node<T = Point> {
+    Leaf{
+        value: Vec<T>,
+    },
+    Branch{
+        nw: Box<Node<T>>,
+        ne: Box<Node<T>>,
+        sw: Box<Node<T>>,
+        se: Box<Node<T>>,
+    }
+}
+
+impl<T> Node<T> {
+    /// Turns a leaf covering `quad` into a branch of four leaves, each with room for at least
+    /// `reserve` items.
+    fn split(&mut self, quad: Quad, reserve: usize, position: &impl Fn(&T) -> Vec2) {
+        if let Node::Leaf{ value } = self {
+            // Routes every point once into a child sized by a counting pass, keeping the relative
+            // order of the points within each child. Against the former two level `partition`,
+            // building 200k points took 69 versus 71 ms at 16 points per quad and 39 versus 42 ms
+            // at 64, but 114 versus 105 ms at 4, where the counting pass doesn't pay off. Splitting
+            // alone is faster at every size, 21 versus 26 ms for 200k points at 4. The old version
+            // is kept as `split_partition`, rerun with
+            // `cargo test --release -- --ignored bench_split --nocapture`.
+            let mut counts = [0; 4];
+            for item in value.iter() {
+                counts[Quadrant::of(quad, position(item)) as usize] += 1;
+            }
+            let mut children = counts.map(|count: usize| Vec::with_capacity(count.max(reserve)));
+            for item in value.drain(..) {
+                children[Quadrant::of(quad, position(&item)) as usize].push(item);
+            }
+            let [nw, ne, sw, se] = children;
+
+            *self = Node::Branch{ 
+                nw: Box::new(Node::Leaf{ value: nw }),
+                ne: Box::new(Node::Leaf{ value: ne }),
+                sw: Box::new(Node::Leaf{ value: sw }),
+                se: Box::new(Node::Leaf{ value: se }),
+            };
+        }
+    }
+
+    /// The two level `partition` that `split` replaced, kept to compare against.
+    #[cfg(test)]
+    fn split_partition(&mut self, quad: Quad, position: &impl Fn(&T) -> Vec2) {
+        if let Node::Leaf{ value } = self {
+            let (x, y, width, height) = quad;
+            let x_mid = x + width / 2.0;
+            let y_mid = y + height / 2.0;
+            let (mut north, mut south): (Vec<T>, Vec<T>) = value.drain(..).partition(|b| position(b).y >= y_mid);
+            let (nw, ne): (Vec<T>, Vec<T>) = north.drain(..).partition(|b| position(b).x < x_mid);
+            let (sw, se): (Vec<T>, Vec<T>) = south.drain(..).partition(|b| position(b).x < x_mid);
+
+            *self = Node::Branch{
+                nw: Box::new(Node::Leaf{ value: nw }),
+                ne: Box::new(Node::Leaf{ value: ne }),
+                sw: Box::new(Node::Leaf{ value: sw }),
+                se: Box::new(Node::Leaf{ value: se }),
+            };
+        }
+    }
+
+    /// Number of items in the subtree, counting no further than `limit`.
+    fn count_up_to(&self, limit: usize) -> usize {
+        match self {
+            Node::Leaf{ value } => value.len(),
+            Node::Branch{ nw, ne, sw, se } => {
+                let mut count = 0;
+                for child in [nw, ne, sw, se] {
+                    if count >= limit {
+                        break;
+                    }
+                    count += child.count_up_to(limit - count);
+                }
+                count
+            }
+        }
+    }
+
+    fn collect_stats(&self, depth: usize, stats: &mut TreeStats) {
+        stats.max_depth = stats.max_depth.max(depth);
+        match self {
+            Node::Leaf{ value } => {
+                stats.points += value.len();
+                stats.leaves += 1;
+            },
+            Node::Branch{ nw, ne, sw, se } => {
+                for child in [nw, ne, sw, se] {
+                    child.collect_stats(depth + 1, stats);
+                }
+            }
+        }
+    }
+
+    /// Turns the subtree into a single leaf holding all of its items.
+    fn collapse(&mut self) {
+        if let Node::Branch{ .. } = self {
+            let mut items = Vec::new();
+            std::mem::replace(self, Node::Leaf{ value: Vec::new() }).drain_into(&mut items);
+            *self = Node::Leaf{ value: items };
+        }
+    }
+
+    fn drain_into(self, items: &mut Vec<T>) {
+        match self {
+            Node::Leaf{ mut value } => items.append(&mut value),
+            Node::Branch{ nw, ne, sw, se } => {
+                for child in [nw, ne, sw, se] {
+                    child.drain_into(items);
+                }
+            }
+        }
+    }
+
+    fn into_largest_buffer(self) -> Vec<T> {
+        match self {
+            Node::Leaf{ value } => value,
+            Node::Branch{ nw, ne, sw, se } => [nw, ne, sw, se].into_iter()
+                .map(|child| child.into_largest_buffer())
+                .max_by_key(|buffer| buffer.capacity())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    stack: Vec<(&'a Node, Quad)>,
+    leaf: std::slice::Iter<'a, Point>,
+    /// Only leaves overlapping this `(min_x, min_y, max_x, max_y)` rectangle are entered.
+    rect: Option<(f32, f32, f32, f32)>,
+    /// Ids already yielded, to skip the copies of a loose tree.
+    seen: Option<HashSet<usize>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Point;
+
+    fn next(&mut self) -> Option<&'a Point> {
+        loop {
+            if let Some(point) = self.leaf.next() {
+                if self.seen.as_mut().is_none_or(|seen| seen.insert(point.id)) {
+                    return Some(point);
+                }
+                continue;
+            }
+            let (node, quad) = self.stack.pop()?;
+            if let Some(rect) = self.rect {
+                let (x, y, width, height) = quad;
+                if !rect_overlaps(rect, x, y, width, height) {
+                    continue;
+                }
+            }
+            match node {
+                Node::Leaf{ value } => self.leaf = value.iter(),
+                Node::Branch{ nw, ne, sw, se } => {
+                    self.stack.push((se, Quadrant::SE.quad(quad)));
+                    self.stack.push((sw, Quadrant::SW.quad(quad)));
+                    self.stack.push((ne, Quadrant::NE.quad(quad)));
+                    self.stack.push((nw, Quadrant::NW.quad(quad)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::{Duration, Instant};
+
+    fn point(id: usize, x: f32, y: f32) -> Point {
+        let position = Vec2::new(x, y);
+        Point::new(id, position, position, Vec2::ZERO, 2.0, Default::default())
+    }
+
+    /// `n` points spread evenly at random over `[0, 1000)²`.
+    fn uniform(n: usize, seed: u64) -> Vec<Point> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|id| point(id, rng.gen_range(0.0..1000.0), rng.gen_range(0.0..1000.0))).collect()
+    }
+
+    /// `n` points in a few tight clusters inside `[0, 1000)²`.
+    fn clustered(n: usize, seed: u64) -> Vec<Point> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let centers = [Vec2::new(150.0, 200.0), Vec2::new(700.0, 750.0), Vec2::new(820.0, 180.0)];
+        (0..n).map(|id| {
+            let center = centers[id % centers.len()];
+            let offset = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)) * rng.gen_range(0.0f32..1.0).powi(3) * 120.0;
+            point(id, center.x + offset.x, center.y + offset.y)
+        }).collect()
+    }
+
+    fn tree(points: &[Point], points_per_quad: usize) -> QuadTree {
+        QuadTree::from_points(points.to_vec(), 0.0, 0.0, 1000.0, 1000.0, points_per_quad)
+    }
+
+    fn sorted_ids<'a>(points: impl IntoIterator<Item = &'a Point>) -> Vec<usize> {
+        let mut ids: Vec<usize> = points.into_iter().map(|p| p.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Fastest of five runs of `f`.
+    fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+        (0..5).map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(f());
+            start.elapsed()
+        }).min().unwrap()
+    }
+
+    #[test]
+    fn traversals_visit_the_same_leaves() {
+        let points = clustered(5000, 1);
+        let tree = tree(&points, 8);
+        for (x, y, radius) in [(150.0, 200.0, 30.0), (500.0, 500.0, 400.0), (999.0, 1.0, 5.0)] {
+            let mut iterative = Vec::new();
+            tree.visit_rect_iterative(x - radius, y - radius, x + radius, y + radius, |leaf| iterative.extend(leaf));
+            assert_eq!(sorted_ids(iterative), sorted_ids(tree.query_radius(x, y, radius)));
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release"]
+    fn bench_traversals() {
+        for (name, points) in [("uniform", uniform(200_000, 2)), ("clustered", clustered(200_000, 2))] {
+            let tree = tree(&points, 16);
+            let queries: Vec<Vec2> = points.iter().step_by(4).map(|p| p.position).collect();
+            let recursive = time(|| queries.iter().map(|q| tree.query_radius(q.x, q.y, 12.0).len()).sum::<usize>());
+            let iterative = time(|| queries.iter().map(|q| {
+                let mut result: Vec<&Point> = Vec::new();
+                tree.visit_rect_iterative(q.x - 12.0, q.y - 12.0, q.x + 12.0, q.y + 12.0, |leaf| result.extend(leaf));
+                result.len()
+            }).sum::<usize>());
+            println!("{}: recursive {:?}, iterative {:?}", name, recursive, iterative);
+        }
+    }
+
+    #[test]
+    fn morton_build_answers_like_from_points() {
+        for points in [uniform(3000, 3), clustered(3000, 3)] {
+            let inserted = tree(&points, 8);
+            let bulk = QuadTree::from_points_morton(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 8);
+            assert_eq!(sorted_ids(bulk.iter()), sorted_ids(inserted.iter()));
+            for query in points.iter().step_by(97) {
+                let (x, y) = (query.position.x, query.position.y);
+                let exact = |tree: &QuadTree| {
+                    sorted_ids(tree.query_radius(x, y, 40.0).into_iter().filter(|p| p.position.distance(query.position) <= 40.0))
+                };
+                assert_eq!(exact(&bulk), exact(&inserted));
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release"]
+    fn bench_morton_build() {
+        for (name, points) in [("uniform", uniform(200_000, 4)), ("clustered", clustered(200_000, 4))] {
+            let inserted = time(|| tree(&points, 16));
+            let bulk = time(|| QuadTree::from_points_morton(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 16));
+            println!("{}: from_points {:?}, from_points_morton {:?}", name, inserted, bulk);
+        }
+    }
+
+    /// The four children of a freshly split node, as indices into the split items.
+    fn children(node: Node<usize>) -> [Vec<usize>; 4] {
+        match node {
+            Node::Branch{ nw, ne, sw, se } => [*nw, *ne, *sw, *se].map(|child| match child {
+                Node::Leaf{ value } => value,
+                Node::Branch{ .. } => panic!("split produced a nested branch"),
+            }),
+            Node::Leaf{ .. } => panic!("split left a leaf"),
+        }
+    }
+
+    #[test]
+    fn split_routes_like_partition() {
+        // Snapped to a 50 unit grid, so plenty of points sit on the midlines.
+        let positions: Vec<Vec2> = uniform(500, 5).iter().map(|p| (p.position / 50.0).round() * 50.0).collect();
+        let position = |i: &usize| positions[*i];
+        for quad in [(0.0, 0.0, 1000.0, 1000.0), (250.0, 250.0, 500.0, 500.0), (0.0, 500.0, 500.0, 500.0)] {
+            let mut counted = Node::Leaf{ value: (0..positions.len()).collect() };
+            let mut partitioned = Node::Leaf{ value: (0..positions.len()).collect() };
+            counted.split(quad, 0, &position);
+            partitioned.split_partition(quad, &position);
+            assert_eq!(children(counted), children(partitioned));
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release"]
+    fn bench_split() {
+        let positions: Vec<Vec2> = uniform(200_000, 6).iter().map(|p| p.position).collect();
+        let position = |i: &usize| positions[*i];
+        for points_per_quad in [4, 16, 64] {
+            // One full leaf per chunk, the size every split in a build sees.
+            let leaves = || -> Vec<Node<usize>> {
+                (0..positions.len()).collect::<Vec<_>>().chunks(points_per_quad + 1).map(|chunk| Node::Leaf{ value: chunk.to_vec() }).collect()
+            };
+            let quad = (0.0, 0.0, 1000.0, 1000.0);
+            let counted = time(|| leaves().iter_mut().for_each(|leaf| leaf.split(quad, 0, &position)));
+            let partitioned = time(|| leaves().iter_mut().for_each(|leaf| leaf.split_partition(quad, &position)));
+            println!("{} points per quad: split {:?}, split_partition {:?}", points_per_quad, counted, partitioned);
+        }
+    }
+
+    #[test]
+    fn uniform_grid_buckets_every_point() {
+        let points = clustered(2000, 9);
+        let grid = tree(&points, 8).to_uniform_grid(25.0);
+        assert_eq!((grid.columns, grid.rows), (40, 40));
+        for point in &points {
+            assert!(grid.cell(grid.cell_of(point.position)).contains(&(point.id as u32)));
+        }
+        let neighbors = grid.neighbor_candidates(500.0, 500.0);
+        for point in points.iter().filter(|p| p.position.distance(Vec2::new(500.0, 500.0)) <= 25.0) {
+            assert!(neighbors.contains(&(point.id as u32)));
+        }
+    }
+
+    #[test]
+    fn tiny_grid_cells_are_capped() {
+        let grid = tree(&uniform(100, 10), 8).to_uniform_grid(1e-6);
+        assert!(grid.columns * grid.rows <= MAX_GRID_CELLS);
+        assert_eq!(grid.ids.len(), 100);
+    }
+
+    #[test]
+    fn loose_radius_queries_yield_each_point_once() {
+        let points = clustered(2000, 11);
+        let loose = QuadTree::from_points_loose(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 4);
+        assert!(loose.stats().points > points.len(), "no point was stored twice");
+        for (x, y, radius) in [(300.0, 300.0, 100.0), (500.0, 500.0, 600.0)] {
+            let expected: Vec<usize> = loose.query_radius(x, y, radius).iter().map(|p| p.id).collect();
+            let mut unique = expected.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), expected.len());
+            assert_eq!(loose.iter_radius(x, y, radius).map(|p| p.id).collect::<Vec<_>>(), expected);
+            assert_eq!(loose.query_radius_owned(x, y, radius).iter().map(|p| p.id).collect::<Vec<_>>(), expected);
+            assert_eq!(sorted_ids(loose.query_radius_par(x, y, radius)), unique);
+        }
+    }
+
+    #[test]
+    fn positions_match_the_stored_points() {
+        let tree = tree(&clustered(500, 12), 8);
+        let expected: Vec<(usize, Vec2)> = tree.iter().map(|p| (p.id, p.position)).collect();
+        assert_eq!(tree.positions(), expected);
+        assert_eq!(expected.len(), 500);
+    }
+
+    #[test]
+    fn iteration_keeps_insertion_order_within_leaves() {
+        // Inserted interleaved, two points per quadrant, so the root splits once.
+        let inserted = [
+            point(0, 600.0, 100.0), point(1, 100.0, 900.0), point(2, 900.0, 900.0), point(3, 100.0, 100.0),
+            point(4, 200.0, 200.0), point(5, 800.0, 800.0), point(6, 200.0, 800.0), point(7, 700.0, 300.0),
+        ];
+        let mut tree = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        for p in inserted {
+            tree.insert(p, 4);
+        }
+        // NW, NE, SW, SE, and within each leaf the order of insertion.
+        assert_eq!(tree.iter().map(|p| p.id).collect::<Vec<_>>(), [1, 6, 2, 5, 3, 4, 0, 7]);
+        // Merging back into one leaf keeps the quadrant order instead of restoring insertion order.
+        tree.set_capacity(32);
+        assert_eq!(tree.stats().leaves, 1);
+        assert_eq!(tree.iter().map(|p| p.id).collect::<Vec<_>>(), [1, 6, 2, 5, 3, 4, 0, 7]);
+    }
+
+    #[test]
+    fn degenerate_dimensions_are_clamped() {
+        for (width, height) in [(0.0, 0.0), (-50.0, 100.0), (f32::NAN, f32::NAN)] {
+            let mut tree = QuadTree::new(0.0, 0.0, width, height);
+            let (_, _, clamped_width, clamped_height) = tree.bounds();
+            assert!(clamped_width >= MIN_SIZE && clamped_height >= MIN_SIZE);
+            // Coincident points would subdivide forever without the depth limit.
+            for id in 0..20 {
+                tree.insert(point(id, 0.0, 0.0), 4);
+            }
+            assert_eq!(tree.query_radius(0.0, 0.0, 1.0).len(), 20);
+            let built = QuadTree::from_points(vec![point(0, 0.0, 0.0)], 0.0, 0.0, width, height, 4);
+            assert_eq!(built.iter().count(), 1);
+        }
+    }
+
+    #[test]
+    fn radius_filter_only_collects_matching_candidates() {
+        let tree = tree(&uniform(2000, 13), 8);
+        let odd = |p: &Point| p.id % 2 == 1;
+        let filtered = tree.query_radius_filter(400.0, 600.0, 150.0, odd);
+        let expected: Vec<&Point> = tree.query_radius(400.0, 600.0, 150.0).into_iter().filter(|p| odd(p)).collect();
+        assert!(!filtered.is_empty());
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn wrapped_query_reaches_across_the_right_edge() {
+        let points = vec![point(0, 5.0, 500.0), point(1, 990.0, 500.0), point(2, 500.0, 500.0), point(3, 3.0, 3.0)];
+        let tree = tree(&points, 1);
+        let ids = sorted_ids(tree.query_radius_wrapped(995.0, 500.0, 20.0).into_iter().filter(|p| {
+            let dx = (p.position.x - 995.0).abs();
+            dx.min(1000.0 - dx).hypot(p.position.y - 500.0) <= 20.0
+        }));
+        assert_eq!(ids, [0, 1]);
+        // Crossing two edges reaches the opposite corner, each point once.
+        let corner = tree.query_radius_wrapped(998.0, 998.0, 10.0);
+        assert_eq!(corner.iter().filter(|p| p.id == 3).count(), 1);
+    }
+
+    #[test]
+    fn obb_query_matches_brute_force() {
+        let points = uniform(3000, 14);
+        let tree = tree(&points, 8);
+        let center = Vec2::new(480.0, 520.0);
+        let half_extents = Vec2::new(200.0, 60.0);
+        for angle in [0.0, 0.4, std::f32::consts::FRAC_PI_2, -2.5] {
+            let expected = sorted_ids(points.iter().filter(|p| {
+                let (axis_x, axis_y) = (Vec2::new(angle.cos(), angle.sin()), Vec2::new(-angle.sin(), angle.cos()));
+                let offset = p.position - center;
+                offset.dot(axis_x).abs() <= half_extents.x && offset.dot(axis_y).abs() <= half_extents.y
+            }));
+            assert!(!expected.is_empty());
+            assert_eq!(sorted_ids(tree.query_obb(center, half_extents, angle)), expected, "angle {}", angle);
+        }
+    }
+
+    #[test]
+    fn try_from_points_returns_exactly_the_rejected_points() {
+        let mut points = uniform(200, 15);
+        points.extend([point(200, -1.0, 500.0), point(201, 500.0, 1000.5), point(202, f32::NAN, 0.0), point(203, 2000.0, -3.0)]);
+        let (tree, rejected) = QuadTree::try_from_points(points, 0.0, 0.0, 1000.0, 1000.0, 8);
+        assert_eq!(rejected.iter().map(|p| p.id).collect::<Vec<_>>(), [200, 201, 202, 203]);
+        assert_eq!(sorted_ids(tree.iter()), (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn radius_bounds_enclose_the_points_within_the_radius() {
+        let points = clustered(2000, 16);
+        let tree = tree(&points, 8);
+        let center = Vec2::new(150.0, 200.0);
+        let inside: Vec<Vec2> = points.iter().map(|p| p.position).filter(|p| p.distance(center) <= 60.0).collect();
+        let min = inside.iter().fold(Vec2::splat(f32::INFINITY), |min, p| min.min(*p));
+        let max = inside.iter().fold(Vec2::splat(f32::NEG_INFINITY), |max, p| max.max(*p));
+        assert_eq!(tree.radius_bounds(center.x, center.y, 60.0), Some((min.x, min.y, max.x, max.y)));
+        assert_eq!(tree.radius_bounds(500.0, 500.0, 10.0), None);
+    }
+
+    #[test]
+    fn set_points_replaces_the_whole_set() {
+        let mut tree = tree(&clustered(1000, 17), 8);
+        let generation = tree.generation();
+        let replacement: Vec<Point> = uniform(300, 18).into_iter().map(|mut p| {
+            p.id += 5000;
+            p
+        }).collect();
+        tree.set_points(replacement.clone(), 8);
+        assert_eq!(tree.generation(), generation + 1);
+        assert_eq!(tree.bounds(), (0.0, 0.0, 1000.0, 1000.0));
+        assert_eq!(sorted_ids(tree.iter()), sorted_ids(&replacement));
+        assert!(tree.query_radius(150.0, 200.0, 50.0).iter().all(|p| p.id >= 5000));
+    }
+
+    #[test]
+    fn degenerate_radii_are_clamped() {
+        for radius in [0.0, -3.0, f32::NAN] {
+            let p = Point::new(0, Vec2::ZERO, Vec2::ZERO, Vec2::ZERO, radius, Default::default());
+            assert_eq!(p.radius, MIN_RADIUS);
+            assert_eq!(p.extent(Vec2::ZERO), MIN_RADIUS);
+        }
+        let mut ellipse = point(0, 0.0, 0.0);
+        ellipse.set_ellipse(0.0, 4.0);
+        assert_eq!(ellipse.semi_axes(), Vec2::new(MIN_RADIUS, 4.0));
+    }
+
+    #[test]
+    fn index_tree_indices_map_back_to_the_points() {
+        let mut points = clustered(2000, 19);
+        // Ids that differ from the indices, so a mixup shows.
+        points.iter_mut().for_each(|p| p.id = 10 * p.id + 3);
+        let index_tree = IndexQuadTree::from_points(&points, 0.0, 0.0, 1000.0, 1000.0, 8);
+        let tree = tree(&points, 8);
+        for (x, y, radius) in [(150.0, 200.0, 40.0), (700.0, 750.0, 200.0), (10.0, 990.0, 5.0)] {
+            let indices = index_tree.query_radius(x, y, radius);
+            assert_eq!(sorted_ids(indices.iter().map(|&i| &points[i])), sorted_ids(tree.query_radius(x, y, radius)));
+        }
+    }
+
+    #[test]
+    fn leaf_neighbors_are_the_other_points_of_the_leaf() {
+        let points = [
+            point(0, 100.0, 100.0), point(1, 150.0, 120.0), point(2, 120.0, 180.0), point(3, 400.0, 400.0),
+            point(4, 100.0, 900.0), point(5, 900.0, 900.0), point(6, 900.0, 100.0),
+        ];
+        // Seven points over a capacity of four split the root once, leaving 0 to 3 in the SW leaf.
+        let tree = tree(&points, 4);
+        assert_eq!(sorted_ids(tree.leaf_neighbors(0, 100.0, 100.0)), [1, 2, 3]);
+        assert_eq!(sorted_ids(tree.leaf_neighbors(3, 499.0, 1.0)), [0, 1, 2]);
+        assert!(tree.leaf_neighbors(5, 900.0, 900.0).is_empty());
+    }
+
+    #[test]
+    fn oscillating_around_the_capacity_keeps_the_structure() {
+        let points = uniform(9, 20);
+        let mut tree = tree(&points[..8], 8);
+        assert_eq!(tree.stats().leaves, 1);
+        tree.insert(points[8], 8);
+        let split = tree.stats();
+        assert_eq!(split.leaves, 4);
+        for _ in 0..10 {
+            let removed = tree.remove(8, points[8].position.x, points[8].position.y, 8).unwrap();
+            assert_eq!(tree.stats(), TreeStats { points: 8, ..split });
+            tree.insert(removed, 8);
+            assert_eq!(tree.stats(), split);
+        }
+        // Below half the capacity the branch merges back.
+        for p in &points[..6] {
+            tree.remove(p.id, p.position.x, p.position.y, 8).unwrap();
+        }
+        assert_eq!(tree.stats(), TreeStats { points: 3, leaves: 1, max_depth: 0 });
+    }
+
+    #[test]
+    fn extend_equals_repeated_inserts() {
+        let mut points = clustered(1500, 21);
+        points.push(point(1500, f32::NAN, 10.0));
+        let mut one_by_one = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        for p in &points {
+            one_by_one.insert(*p, 8);
+        }
+        let mut extended = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        assert_eq!(extended.extend(points.iter().copied(), 8), 1);
+        assert_eq!(sorted_ids(extended.iter()), sorted_ids(one_by_one.iter()));
+        for p in points.iter().step_by(50).filter(|p| p.position.is_finite()) {
+            let (x, y) = (p.position.x, p.position.y);
+            assert_eq!(sorted_ids(extended.query_radius(x, y, 30.0)), sorted_ids(one_by_one.query_radius(x, y, 30.0)));
+        }
+    }
+
+    #[test]
+    fn set_capacity_restructures_in_place() {
+        let points = uniform(2000, 22);
+        let mut restructured = tree(&points, 32);
+        let initial = restructured.stats();
+        restructured.set_capacity(4);
+        let lowered = restructured.stats();
+        assert!(lowered.max_depth > initial.max_depth && lowered.leaves > initial.leaves);
+        restructured.set_capacity(256);
+        let raised = restructured.stats();
+        assert!(raised.max_depth < initial.max_depth && raised.leaves < initial.leaves);
+        assert_eq!(raised.points, 2000);
+        let fresh = tree(&points, 256);
+        for p in points.iter().step_by(100) {
+            let (x, y) = (p.position.x, p.position.y);
+            let exact = |tree: &QuadTree| sorted_ids(tree.query_radius(x, y, 50.0).into_iter().filter(|q| q.position.distance(p.position) <= 50.0));
+            assert_eq!(exact(&restructured), exact(&fresh));
+        }
+    }
+
+    #[test]
+    fn outside_radius_is_the_brute_force_complement() {
+        let points = clustered(3000, 23);
+        let tree = tree(&points, 8);
+        for (x, y, radius) in [(150.0, 200.0, 100.0), (500.0, 500.0, 450.0), (0.0, 0.0, 2000.0)] {
+            let center = Vec2::new(x, y);
+            let expected = sorted_ids(points.iter().filter(|p| p.position.distance(center) > radius));
+            assert_eq!(sorted_ids(tree.query_outside_radius(x, y, radius)), expected);
+        }
+    }
+
+    #[test]
+    fn iter_radius_is_lazy_and_matches_query_radius() {
+        let tree = tree(&uniform(3000, 24), 8);
+        for (x, y, radius) in [(500.0, 500.0, 300.0), (20.0, 980.0, 40.0)] {
+            let lazy: Vec<&Point> = tree.iter_radius(x, y, radius).collect();
+            assert_eq!(lazy, tree.query_radius(x, y, radius));
+        }
+        // The first point is found with most of the overlapping subtrees still unvisited.
+        let mut iter = tree.iter_radius(500.0, 500.0, 300.0);
+        assert!(iter.next().is_some());
+        assert!(iter.stack.len() > 4);
+    }
+
+    #[test]
+    fn samples_land_within_jitter_of_a_point() {
+        let points = clustered(500, 25);
+        let tree = tree(&points, 8);
+        let mut rng = StdRng::seed_from_u64(25);
+        for _ in 0..200 {
+            let sample = tree.sample_near_points(&mut rng, 15.0).unwrap();
+            assert!(points.iter().any(|p| p.position.distance(sample) <= 15.0 + 1e-3));
+        }
+        assert_eq!(QuadTree::new(0.0, 0.0, 10.0, 10.0).sample_near_points(&mut rng, 15.0), None);
+    }
+
+    #[test]
+    fn quadrant_counts_follow_a_skewed_spread() {
+        // 60 points NE, 25 SW, 10 SE, 5 NW, and one on the center going NE by the midline rule.
+        let mut points = Vec::new();
+        for (count, x, y) in [(60, 750.0, 750.0), (25, 250.0, 250.0), (10, 750.0, 250.0), (5, 250.0, 750.0)] {
+            let first = points.len();
+            points.extend((0..count).map(|i| point(first + i, x + i as f32, y - i as f32)));
+        }
+        points.push(point(points.len(), 500.0, 500.0));
+        assert_eq!(tree(&points, 8).quadrant_counts(), [5, 61, 25, 10]);
+        // An unsplit root counts with the same rule.
+        assert_eq!(tree(&points, 1000).quadrant_counts(), [5, 61, 25, 10]);
+    }
+
+    #[test]
+    fn growing_tree_takes_in_far_points() {
+        let mut points = uniform(300, 26);
+        points.push(point(300, 5300.0, -2100.0));
+        let tree = QuadTree::from_points_growing(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 8);
+        let (x, y, width, height) = tree.bounds();
+        // Doubled three times, twice growing down and once up once the point was in reach.
+        assert_eq!((x, y, width, height), (0.0, -3000.0, 8000.0, 8000.0));
+        assert_eq!(sorted_ids(tree.iter()), sorted_ids(&points));
+        assert_eq!(tree.nearest(5300.0, -2100.0).map(|p| p.id), Some(300));
+        for p in points.iter().step_by(30) {
+            assert!(tree.query_radius(p.position.x, p.position.y, 1.0).iter().any(|q| q.id == p.id));
+        }
+        let mut grown = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        assert_eq!(grown.insert_growing(point(0, f32::INFINITY, 0.0), 8).map(|p| p.id), Some(0));
+        grown.insert(point(1, 10.0, 10.0), 8);
+        // Doubling towards f32::MIN overflows to infinite bounds before it gets there.
+        assert_eq!(grown.insert_growing(point(2, f32::MIN, 0.0), 8).map(|p| p.id), Some(2));
+        assert_eq!(grown.insert_growing(point(3, 0.0, f32::MIN * 0.999), 8).map(|p| p.id), Some(3));
+        assert_eq!(grown.bounds(), (0.0, 0.0, 1000.0, 1000.0));
+        assert_eq!(sorted_ids(grown.iter()), vec![1]);
+        assert_eq!(grown.insert_growing(point(4, -1.0e30, 0.0), 8), None);
+        assert!(grown.has_finite_bounds() && grown.contains(Vec2::new(-1.0e30, 0.0)));
+    }
+
+    #[test]
+    fn contacts_of_a_small_overlapping_configuration() {
+        // Radius 2 each: 0 overlaps 1 by 1 and 2 by 0.5, 1 and 2 are 4.6 apart.
+        let points = [point(0, 10.0, 10.0), point(1, 13.0, 10.0), point(2, 10.0, 13.5), point(3, 50.0, 50.0)];
+        let tree = IndexQuadTree::from_points(&points, 0.0, 0.0, 100.0, 100.0, 1);
+        let mut contacts = tree.contacts(&points);
+        contacts.sort_by_key(|c| (c.a, c.b));
+        assert_eq!(contacts, [
+            Contact { a: 0, b: 1, normal: Vec2::new(-1.0, 0.0), depth: 1.0 },
+            Contact { a: 0, b: 2, normal: Vec2::new(0.0, -1.0), depth: 0.5 },
+        ]);
+    }
+
+    #[test]
+    fn leaf_pairs_find_every_contact_when_leaves_are_large_enough() {
+        let points = uniform(1500, 27);
+        let tree = IndexQuadTree::from_points(&points, 0.0, 0.0, 1000.0, 1000.0, 8);
+        // Radius 2 everywhere, so contacts are at most 4 apart, below the smallest leaf.
+        assert!(1000.0 / (1 << tree.stats().max_depth) as f32 >= 4.0);
+        let touching = |&(a, b): &(usize, usize)| points[a].position.distance(points[b].position) <= 4.0;
+        let mut by_leaf: Vec<(usize, usize)> = tree.leaf_pairs().into_iter().filter(touching).collect();
+        let mut by_radius: Vec<(usize, usize)> = tree.pairs_within(&points, 4.0).into_iter().filter(touching).collect();
+        by_leaf.sort_unstable();
+        by_radius.sort_unstable();
+        assert!(!by_radius.is_empty());
+        assert_eq!(by_leaf, by_radius);
+    }
+
+    #[test]
+    fn descent_path_follows_the_quadrants_to_the_leaf() {
+        let points = [
+            point(0, 100.0, 100.0), point(1, 150.0, 150.0), point(2, 300.0, 300.0), point(3, 400.0, 400.0),
+            point(4, 900.0, 900.0), point(5, 100.0, 400.0),
+        ];
+        // The fifth point splits the root, the sixth the SW quadrant.
+        let tree = tree(&points, 4);
+        assert_eq!(tree.stats().max_depth, 2);
+        assert_eq!(tree.descent_path(120.0, 120.0), [Quadrant::SW, Quadrant::SW]);
+        assert_eq!(tree.descent_path(400.0, 100.0), [Quadrant::SW, Quadrant::SE]);
+        assert_eq!(tree.descent_path(900.0, 900.0), [Quadrant::NE]);
+        assert!(QuadTree::from_points(points[..4].to_vec(), 0.0, 0.0, 1000.0, 1000.0, 4).descent_path(120.0, 120.0).is_empty());
+    }
+
+    #[test]
+    fn nearest_excluding_skips_the_excluded_points() {
+        let mut points = uniform(1000, 28);
+        points.extend([point(1000, 500.0, 500.0), point(1001, 503.0, 500.0), point(1002, 500.0, 506.0)]);
+        let tree = tree(&points, 8);
+        assert_eq!(tree.nearest(500.5, 500.0).map(|p| p.id), Some(1000));
+        assert_eq!(tree.nearest_excluding(500.5, 500.0, &[1000]).map(|p| p.id), Some(1001));
+        let exclude = [1000, 1001];
+        let center = Vec2::new(500.5, 500.0);
+        let expected = points.iter().filter(|p| !exclude.contains(&p.id)).min_by(|a, b| a.position.distance(center).total_cmp(&b.position.distance(center)));
+        assert_eq!(tree.nearest_excluding(500.5, 500.0, &exclude).map(|p| p.id), expected.map(|p| p.id));
+        let everything: Vec<usize> = (0..1003).collect();
+        assert!(tree.nearest_excluding(500.0, 500.0, &everything).is_none());
+    }
+
+    #[test]
+    fn owned_query_copies_the_referenced_points() {
+        let tree = tree(&clustered(1000, 29), 8);
+        let borrowed = tree.query_radius(700.0, 750.0, 80.0);
+        let owned = tree.query_radius_owned(700.0, 750.0, 80.0);
+        assert!(!owned.is_empty());
+        assert!(owned.iter().eq(borrowed));
+    }
+
+    #[test]
+    fn custom_query_reimplements_query_radius() {
+        let points = clustered(3000, 30);
+        let tree = tree(&points, 8);
+        for (x, y, radius) in [(150.0, 200.0, 50.0), (600.0, 400.0, 300.0)] {
+            let center = Vec2::new(x, y);
+            let custom = tree.query_custom(|quad_x, quad_y, width, height| {
+                let closest = center.clamp(Vec2::new(quad_x, quad_y), Vec2::new(quad_x + width, quad_y + height));
+                closest.distance(center) <= radius
+            }, |p| p.position.distance(center) <= radius);
+            let expected: Vec<&Point> = tree.query_radius(x, y, radius).into_iter().filter(|p| p.position.distance_squared(center) <= radius * radius).collect();
+            assert!(!custom.is_empty());
+            assert_eq!(custom, expected);
+        }
+    }
+
+    #[test]
+    fn set_velocity_round_trips() {
+        let mut p = point(0, 40.0, -7.5);
+        for velocity in [Vec2::new(3.0, -1.25), Vec2::ZERO, Vec2::new(-0.5, 1e3)] {
+            p.set_velocity(velocity);
+            assert_eq!(p.velocity(), velocity);
+            assert_eq!(p.position, Vec2::new(40.0, -7.5));
+        }
+    }
+
+    #[test]
+    fn depth_limit_keeps_tight_clusters_in_deep_leaves() {
+        let mut rng = StdRng::seed_from_u64(31);
+        let cluster: Vec<Point> = (0..500).map(|id| point(id, 400.0 + rng.gen_range(0.0..0.01), 600.0 + rng.gen_range(0.0..0.01))).collect();
+        for max_depth in [0, 3, 8] {
+            let mut tree = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+            tree.set_max_depth(max_depth);
+            for p in &cluster {
+                tree.insert(*p, 4);
+            }
+            assert_eq!(tree.stats().max_depth, max_depth);
+            assert_eq!(tree.query_radius(400.0, 600.0, 1.0).len(), 500);
+        }
+        assert!(tree(&cluster, 4).stats().max_depth <= MAX_DEPTH);
+    }
+
+    #[test]
+    fn two_separate_chains_form_two_components() {
+        // Two chains of points 3 apart, interleaved in the slice, 100 apart from each other.
+        let points: Vec<Point> = (0..20).map(|i| {
+            let (chain, step) = (i % 2, (i / 2) as f32);
+            point(i, 100.0 + 3.0 * step, 100.0 + 100.0 * chain as f32)
+        }).collect();
+        let tree = IndexQuadTree::from_points(&points, 0.0, 0.0, 1000.0, 1000.0, 2);
+        let evens: Vec<usize> = (0..20).step_by(2).collect();
+        let odds: Vec<usize> = (1..20).step_by(2).collect();
+        assert_eq!(tree.connected_components(&points, 3.5), [evens, odds]);
+        // Below the spacing every point stands alone.
+        assert_eq!(tree.connected_components(&points, 2.5).len(), 20);
+    }
+
+    #[test]
+    fn k_nearest_within_stops_at_the_radius() {
+        let points = [point(0, 510.0, 500.0), point(1, 500.0, 503.0), point(2, 500.0, 530.0), point(3, 480.0, 480.0), point(4, 800.0, 800.0)];
+        let tree = tree(&points, 1);
+        let ids = |found: Vec<&Point>| found.iter().map(|p| p.id).collect::<Vec<_>>();
+        // Five requested, only three within 29: 3 away, 10 away and 28.3 away.
+        assert_eq!(ids(tree.k_nearest_within(500.0, 500.0, 5, 29.0)), [1, 0, 3]);
+        assert_eq!(ids(tree.k_nearest_within(500.0, 500.0, 2, 29.0)), [1, 0]);
+        assert!(tree.k_nearest_within(500.0, 500.0, 5, 1.0).is_empty());
+    }
+
+    #[test]
+    fn jittered_inserts_keep_coincident_points_shallow() {
+        let mut rng = StdRng::seed_from_u64(32);
+        let mut jittered = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        let mut exact = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        for id in 0..200 {
+            jittered.insert_jittered(point(id, 500.0, 500.0), 4, 5.0, &mut rng);
+            exact.insert_jittered(point(id, 500.0, 500.0), 4, 0.0, &mut rng);
+        }
+        assert_eq!(exact.stats().max_depth, MAX_DEPTH);
+        assert!(jittered.stats().max_depth <= 12, "depth {}", jittered.stats().max_depth);
+        assert!(jittered.iter().all(|p| p.position.distance(Vec2::splat(500.0)) <= 5.0 * std::f32::consts::SQRT_2));
+        assert!(exact.iter().all(|p| p.position == Vec2::splat(500.0)));
+    }
+
+    #[test]
+    fn leaves_in_rect_visits_only_overlapping_leaves() {
+        let points = uniform(2000, 33);
+        let tree = tree(&points, 8);
+        let (x, y, width, height) = (200.0, 300.0, 150.0, 100.0);
+        let inside = |p: &&Point| p.position.x >= x && p.position.x <= x + width && p.position.y >= y && p.position.y <= y + height;
+        let mut found = Vec::new();
+        let mut visited = 0;
+        tree.leaves_in_rect(x, y, width, height, |leaf, quad_x, quad_y, quad_width, quad_height| {
+            visited += 1;
+            assert!(quad_x <= x + width && quad_x + quad_width >= x && quad_y <= y + height && quad_y + quad_height >= y);
+            for p in leaf {
+                assert!(p.position.x >= quad_x && p.position.x <= quad_x + quad_width && p.position.y >= quad_y && p.position.y <= quad_y + quad_height);
+            }
+            found.extend(leaf.iter().filter(inside).map(|p| p.id));
+        });
+        assert!(visited < tree.stats().leaves);
+        found.sort_unstable();
+        assert_eq!(found, sorted_ids(points.iter().filter(inside)));
+    }
+
+    #[test]
+    fn centroid_is_the_average_position() {
+        let points = clustered(999, 34);
+        let average = points.iter().fold(Vec2::ZERO, |sum, p| sum + p.position) / points.len() as f32;
+        assert!(tree(&points, 8).centroid().unwrap().abs_diff_eq(average, 1e-2));
+        assert_eq!(QuadTree::new(0.0, 0.0, 1.0, 1.0).centroid(), None);
+    }
+
+    #[test]
+    fn nearest_per_direction_assigns_each_side() {
+        // One point straight out on each side, plus a farther one to the left and one out of range.
+        let points = [point(0, 490.0, 500.0), point(1, 520.0, 500.0), point(2, 500.0, 530.0), point(3, 500.0, 460.0), point(4, 470.0, 500.0), point(5, 600.0, 500.0)];
+        let tree = tree(&points, 1);
+        let ids = tree.nearest_per_direction(500.0, 500.0, 50.0).map(|p| p.map(|p| p.id));
+        // Left, right, above, below.
+        assert_eq!(ids, [Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(tree.nearest_per_direction(500.0, 500.0, 15.0).map(|p| p.map(|p| p.id)), [Some(0), None, None, None]);
+    }
+
+    #[test]
+    fn broad_phase_covers_elliptical_contacts() {
+        let mut rng = StdRng::seed_from_u64(35);
+        let points: Vec<Point> = (0..600).map(|id| {
+            let mut p = point(id, rng.gen_range(0.0..300.0), rng.gen_range(0.0..300.0));
+            p.set_ellipse(rng.gen_range(1.0..12.0), rng.gen_range(1.0..12.0));
+            p
+        }).collect();
+        let max_radius = points.iter().map(|p| p.radius).fold(0.0, f32::max);
+        let tree = IndexQuadTree::from_points(&points, 0.0, 0.0, 300.0, 300.0, 8);
+        let candidates: HashSet<(usize, usize)> = tree.pairs_within(&points, max_radius * 2.0).into_iter().collect();
+        let mut contacts = 0;
+        for a in 0..points.len() {
+            for b in a + 1..points.len() {
+                let axis = points[a].position - points[b].position;
+                assert!(points[a].extent(axis) <= points[a].radius + 1e-4);
+                if axis.length() <= points[a].extent(axis) + points[b].extent(axis) {
+                    contacts += 1;
+                    assert!(candidates.contains(&(a, b)), "contact {} {} missed", a, b);
+                }
+            }
+        }
+        assert!(contacts > 0);
+    }
+
+    #[test]
+    fn radial_profile_matches_a_brute_force_histogram() {
+        let points = clustered(3000, 36);
+        let tree = tree(&points, 8);
+        let center = Vec2::new(700.0, 750.0);
+        let mut expected = vec![0; 8];
+        for p in &points {
+            let shell = (p.position.distance(center) / 15.0) as usize;
+            if shell < 8 {
+                expected[shell] += 1;
+            }
+        }
+        assert_eq!(tree.radial_profile(center.x, center.y, 15.0, 8), expected);
+        assert!(tree.radial_profile(center.x, center.y, 0.0, 8).iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn region_emptiness_over_empty_and_occupied_regions() {
+        let points = clustered(2000, 37);
+        let tree = tree(&points, 8);
+        for (x, y, width, height) in [(400.0, 400.0, 100.0, 100.0), (100.0, 150.0, 100.0, 100.0), (0.0, 0.0, 1000.0, 1000.0), (300.0, 500.0, 0.0, 0.0)] {
+            let occupied = points.iter().any(|p| p.position.x >= x && p.position.x <= x + width && p.position.y >= y && p.position.y <= y + height);
+            assert_eq!(tree.is_region_empty(x, y, width, height), !occupied, "({}, {}, {}, {})", x, y, width, height);
+        }
+        assert!(tree.is_region_empty(400.0, 400.0, 100.0, 100.0));
+        assert!(!tree.is_region_empty(100.0, 150.0, 100.0, 100.0));
+    }
+
+    #[test]
+    fn tags_survive_the_tree_and_filter_queries() {
+        let points: Vec<Point> = uniform(1000, 38).into_iter().map(|mut p| {
+            p.tag = (p.id % 3) as u64;
+            p
+        }).collect();
+        let tree = tree(&points, 8);
+        assert!(tree.iter().all(|p| p.tag == (p.id % 3) as u64));
+        let tagged = tree.query_radius_filter(500.0, 500.0, 200.0, |p| p.tag == 2);
+        assert!(!tagged.is_empty());
+        assert_eq!(sorted_ids(tagged), sorted_ids(tree.query_radius(500.0, 500.0, 200.0).into_iter().filter(|p| p.id % 3 == 2)));
+    }
+
+    #[test]
+    fn radius_query_distances_are_exact() {
+        let points = clustered(2000, 39);
+        let tree = tree(&points, 8);
+        let center = Vec2::new(820.0, 180.0);
+        let found = tree.query_radius_with_distance(center.x, center.y, 60.0);
+        assert!(found.iter().all(|&(p, distance)| distance <= 60.0 && (distance - p.position.distance(center)).abs() < 1e-3));
+        assert_eq!(sorted_ids(found.iter().map(|&(p, _)| p)), sorted_ids(points.iter().filter(|p| p.position.distance(center) <= 60.0)));
+    }
+
+    #[test]
+    fn quadrants_together_answer_like_the_whole_tree() {
+        let points = clustered(2000, 40);
+        for points_per_quad in [8, 5000] {
+            let whole = tree(&points, points_per_quad);
+            let quadrants = tree(&points, points_per_quad).into_quadrants();
+            let bounds = quadrants.each_ref().map(|tree| tree.bounds());
+            assert_eq!(bounds, [(0.0, 500.0, 500.0, 500.0), (500.0, 500.0, 500.0, 500.0), (0.0, 0.0, 500.0, 500.0), (500.0, 0.0, 500.0, 500.0)]);
+            assert_eq!(sorted_ids(quadrants.iter().flat_map(|tree| tree.iter())), sorted_ids(whole.iter()));
+            for (x, y, radius) in [(500.0, 500.0, 100.0), (150.0, 200.0, 60.0)] {
+                let exact = |found: Vec<&Point>| sorted_ids(found.into_iter().filter(|p| p.position.distance(Vec2::new(x, y)) <= radius));
+                let parts = quadrants.iter().flat_map(|tree| tree.query_radius(x, y, radius)).collect();
+                assert_eq!(exact(parts), exact(whole.query_radius(x, y, radius)));
+            }
+        }
+    }
+
+    #[test]
+    fn diff_reports_moved_and_unmatched_ids() {
+        let before = [point(0, 0.0, 0.0), point(1, 10.0, 10.0), point(2, 20.0, 20.0), point(3, 30.0, 30.0)];
+        let mut after = before.to_vec();
+        after[1].position.x += 0.05;
+        after[2].position.y -= 3.0;
+        after.remove(3);
+        after.push(point(4, 40.0, 40.0));
+        // Order doesn't matter, points are matched by id.
+        after.reverse();
+        assert_eq!(diff_points(&before, &after, 0.1), [2, 3, 4]);
+        assert_eq!(diff_points(&before, &after, 0.0), [1, 2, 3, 4]);
+        assert!(diff_points(&before, &before, 0.0).is_empty());
+    }
+
+    #[test]
+    fn loose_tree_finds_large_points_from_adjacent_leaves() {
+        let mut points = uniform(1000, 41);
+        let big = Point::new(1000, Vec2::new(490.0, 490.0), Vec2::new(490.0, 490.0), Vec2::ZERO, 60.0, Default::default());
+        points.push(big);
+        let loose = QuadTree::from_points_loose(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 4);
+        let strict = tree(&points, 4);
+        // Inside the big circle but in other leaves than its center, with a small query radius.
+        for (x, y) in [(530.0, 500.0), (480.0, 535.0), (520.0, 520.0)] {
+            assert!(loose.query_radius(x, y, 1.0).iter().any(|p| p.id == 1000), "missed from ({}, {})", x, y);
+            assert!(!strict.query_radius(x, y, 1.0).iter().any(|p| p.id == 1000));
+        }
+        assert_eq!(loose.query_radius(500.0, 500.0, 100.0).iter().filter(|p| p.id == 1000).count(), 1);
+    }
+
+    #[test]
+    fn rect_mutation_only_touches_points_in_the_rect() {
+        let points = uniform(2000, 42);
+        let mut tree = tree(&points, 8);
+        let red = rgb::Rgb::new(1.0, 0.0, 0.0);
+        tree.for_each_in_rect_mut(100.0, 600.0, 250.0, 150.0, |p| p.color = red);
+        let inside = |p: &Point| p.position.x >= 100.0 && p.position.x <= 350.0 && p.position.y >= 600.0 && p.position.y <= 750.0;
+        assert!(tree.iter().any(inside));
+        for p in tree.iter() {
+            assert_eq!(p.color == red, inside(p), "point {}", p.id);
+        }
+    }
+
+    #[test]
+    fn debug_string_outlines_a_small_tree() {
+        let points = vec![point(0, 10.0, 10.0), point(1, 60.0, 60.0), point(2, 20.0, 80.0)];
+        let tree = QuadTree::from_points(points, 0.0, 0.0, 100.0, 100.0, 2);
+        assert_eq!(tree.to_debug_string(), "\
+Root branch (0.0, 0.0, 100.0, 100.0)
+  NW leaf (0.0, 50.0, 50.0, 50.0): 1 points [2]
+  NE leaf (50.0, 50.0, 50.0, 50.0): 1 points [1]
+  SW leaf (0.0, 0.0, 50.0, 50.0): 1 points [0]
+  SE leaf (50.0, 0.0, 50.0, 50.0): 0 points []
+");
+    }
+
+    #[test]
+    fn farthest_matches_brute_force() {
+        let points = clustered(3000, 43);
+        let tree = tree(&points, 8);
+        for center in [Vec2::new(150.0, 200.0), Vec2::new(500.0, 500.0), Vec2::new(-300.0, 1200.0)] {
+            let farthest = tree.farthest(center.x, center.y).unwrap();
+            let expected = points.iter().map(|p| p.position.distance(center)).fold(0.0, f32::max);
+            assert_eq!(farthest.position.distance(center), expected);
+        }
+        assert!(QuadTree::new(0.0, 0.0, 1.0, 1.0).farthest(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn banded_query_sorts_points_by_distance() {
+        // Distances 10, 48, 50, 51.5 and 60 from the center.
+        let points = [point(0, 510.0, 500.0), point(1, 500.0, 548.0), point(2, 450.0, 500.0), point(3, 500.0, 448.5), point(4, 560.0, 500.0)];
+        let tree = tree(&points, 1);
+        let (inside, band) = tree.query_radius_banded(500.0, 500.0, 50.0, 2.0);
+        assert_eq!(sorted_ids(inside), [0]);
+        assert_eq!(sorted_ids(band), [1, 2, 3]);
+        // A negative band counts like a positive one.
+        let (inside, band) = tree.query_radius_banded(500.0, 500.0, 50.0, -2.0);
+        assert_eq!((sorted_ids(inside), sorted_ids(band)), (vec![0], vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn non_finite_positions_are_skipped() {
+        let points = uniform(500, 44);
+        let mut tree = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        for (i, p) in points.iter().enumerate() {
+            tree.insert(*p, 4);
+            if i % 100 == 0 {
+                assert!(!tree.insert(point(1000 + i, f32::NAN, 500.0), 4));
+                assert!(!tree.insert(point(2000 + i, 500.0, f32::INFINITY), 4));
+            }
+        }
+        assert_eq!(sorted_ids(tree.iter()), sorted_ids(&points));
+        let center = points[7].position;
+        let found = tree.query_radius(center.x, center.y, 20.0).into_iter().filter(|p| p.position.distance(center) <= 20.0);
+        assert_eq!(sorted_ids(found), sorted_ids(points.iter().filter(|p| p.position.distance(center) <= 20.0)));
+    }
+
+    #[test]
+    fn margin_keeps_edge_points_queryable() {
+        let edges = vec![point(0, 0.0, 0.0), point(1, 1000.0, 1000.0), point(2, 1000.0, 300.0), point(3, 600.0, 1000.0), point(4, 0.0, 1000.0)];
+        let mut points = uniform(300, 45);
+        points.iter_mut().for_each(|p| p.id += 10);
+        points.extend(edges.iter().copied());
+        let tree = QuadTree::from_points_with_margin(points, 0.0, 0.0, 1000.0, 1000.0, 4, 1.0);
+        assert_eq!(tree.bounds(), (-1.0, -1.0, 1002.0, 1002.0));
+        for p in &edges {
+            assert!(tree.query_radius(p.position.x, p.position.y, 0.5).iter().any(|q| q.id == p.id), "edge point {}", p.id);
+        }
+    }
+
+    #[test]
+    fn morton_iteration_is_monotonic_in_the_code() {
+        let tree = tree(&uniform(300, 46), 4);
+        let codes: Vec<u32> = tree.iter_morton().map(|p| tree.morton_code(p.position)).collect();
+        assert_eq!(codes.len(), 300);
+        assert!(codes.windows(2).all(|pair| pair[0] <= pair[1]));
+        // The Z curve visits the bottom left corner first and the top right corner last.
+        let corners = QuadTree::from_points(vec![point(0, 900.0, 900.0), point(1, 100.0, 900.0), point(2, 900.0, 100.0), point(3, 100.0, 100.0)], 0.0, 0.0, 1000.0, 1000.0, 4);
+        assert_eq!(corners.iter_morton().map(|p| p.id).collect::<Vec<_>>(), [3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn grouped_query_flattens_to_query_radius() {
+        let tree = tree(&clustered(2000, 47), 8);
+        let groups = tree.query_radius_by_leaf(700.0, 750.0, 90.0);
+        assert!(groups.len() > 1 && groups.iter().all(|group| !group.is_empty()));
+        assert_eq!(groups.concat(), tree.query_radius(700.0, 750.0, 90.0));
+        // Every group is the full content of one leaf.
+        for group in &groups {
+            let first = group[0];
+            let mut leaf = tree.leaf_neighbors(first.id, first.position.x, first.position.y);
+            leaf.insert(0, first);
+            assert_eq!(sorted_ids(group.iter().copied()), sorted_ids(leaf));
+        }
+    }
+
+    #[test]
+    fn clustered_points_are_less_balanced_than_uniform_ones() {
+        let uniform = tree(&uniform(4000, 48), 16).balance_factor();
+        let clustered = tree(&clustered(4000, 48), 16).balance_factor();
+        assert!(clustered > uniform, "clustered {} versus uniform {}", clustered, uniform);
+        assert_eq!(QuadTree::new(0.0, 0.0, 1.0, 1.0).balance_factor(), 0.0);
+    }
+
+    #[test]
+    fn rect_filter_radius_skips_small_points() {
+        let points: Vec<Point> = uniform(2000, 49).into_iter().map(|mut p| {
+            p.radius = if p.id % 3 == 0 { 8.0 } else { 2.0 };
+            p
+        }).collect();
+        let quadtree = tree(&points, 8);
+        let inside = |p: &Point| p.position.x >= 200.0 && p.position.x <= 600.0 && p.position.y >= 100.0 && p.position.y <= 400.0;
+        let expected: Vec<usize> = points.iter().filter(|p| inside(p) && p.radius >= 5.0).map(|p| p.id).collect();
+        assert!(!expected.is_empty());
+        assert_eq!(sorted_ids(quadtree.query_rect_filter_radius(200.0, 100.0, 400.0, 300.0, 5.0)), expected);
+        assert_eq!(quadtree.query_rect_filter_radius(200.0, 100.0, 400.0, 300.0, 0.0).len(), points.iter().filter(|p| inside(p)).count());
+        assert!(quadtree.query_rect_filter_radius(200.0, 100.0, 400.0, 300.0, 9.0).is_empty());
+    }
+
+    #[test]
+    fn sparsest_leaf_is_found_in_the_empty_quadrant() {
+        let points: Vec<Point> = uniform(3000, 50).into_iter().filter(|p| p.position.x < 500.0 || p.position.y < 500.0).collect();
+        let quadtree = tree(&points, 8);
+        assert_eq!(quadtree.sparsest_leaf_near(400.0, 400.0, 300.0), Some((500.0, 500.0, 500.0, 500.0)));
+        let (x, y, width, height) = quadtree.sparsest_leaf_near(100.0, 100.0, 50.0).unwrap();
+        assert!(x + width <= 500.0 && y + height <= 500.0, "{:?}", (x, y, width, height));
+        assert_eq!(quadtree.sparsest_leaf_near(-500.0, -500.0, 10.0), None);
+    }
+
+    #[test]
+    fn any_within_agrees_with_radius_queries() {
+        let mut rng = StdRng::seed_from_u64(51);
+        for points in [uniform(40, 51), uniform(5000, 52)] {
+            let quadtree = tree(&points, 8);
+            let mut hits = 0;
+            for _ in 0..500 {
+                let (x, y, radius) = (rng.gen_range(-50.0..1050.0), rng.gen_range(-50.0..1050.0), rng.gen_range(0.0..60.0));
+                let center = Vec2::new(x, y);
+                // `query_radius` returns whole leaves, so its candidates are narrowed down by distance.
+                let found: Vec<&Point> = quadtree.query_radius(x, y, radius).into_iter().filter(|p| p.position.distance_squared(center) <= radius * radius).collect();
+                assert_eq!(quadtree.any_within(x, y, radius, None), !found.is_empty());
+                if let [only] = found[..] {
+                    assert!(!quadtree.any_within(x, y, radius, Some(only.id)));
+                }
+                hits += found.len().min(1);
+            }
+            assert!(hits > 0 && hits < 500, "{} of 500 queries hit", hits);
+        }
+    }
+
+    #[test]
+    fn newer_than_returns_only_points_added_after_the_build() {
+        let mut quadtree = tree(&[], 8);
+        quadtree.set_generation(4);
+        let old: Vec<Point> = uniform(500, 53).into_iter().map(|mut p| {
+            p.spawn_generation = quadtree.generation();
+            p
+        }).collect();
+        quadtree.set_points(old, 8);
+        let built = quadtree.generation();
+        assert_eq!(built, 5);
+        let fresh: Vec<Point> = uniform(100, 54).into_iter().map(|mut p| {
+            p.id += 1000;
+            p.spawn_generation = built + 1;
+            p
+        }).collect();
+        for point in &fresh {
+            assert!(quadtree.insert(*point, 8));
+        }
+        let newer = quadtree.query_radius_newer_than(500.0, 500.0, 300.0, built);
+        assert!(!newer.is_empty());
+        assert_eq!(sorted_ids(newer), sorted_ids(quadtree.query_radius(500.0, 500.0, 300.0).into_iter().filter(|p| p.id >= 1000)));
+        assert!(quadtree.query_radius_newer_than(500.0, 500.0, 300.0, built + 1).is_empty());
+    }
+
+    #[test]
+    fn occupancy_histogram_buckets_known_leaves() {
+        // Three points SW, two NE and one SE split the root into leaves of 3, 2, 1 and 0 points.
+        let points = [point(0, 100.0, 100.0), point(1, 200.0, 150.0), point(2, 300.0, 300.0), point(3, 700.0, 700.0), point(4, 800.0, 900.0), point(5, 900.0, 100.0)];
+        let quadtree = tree(&points, 4);
+        assert_eq!(quadtree.occupancy_histogram(1), vec![1, 1, 1, 1]);
+        assert_eq!(quadtree.occupancy_histogram(0), vec![1, 1, 1, 1]);
+        assert_eq!(quadtree.occupancy_histogram(2), vec![2, 2]);
+        assert_eq!(quadtree.occupancy_histogram(4), vec![4]);
+        assert_eq!(tree(&[], 4).occupancy_histogram(1), vec![1]);
+        // Every leaf of a large uniform tree is counted once.
+        let quadtree = tree(&uniform(5000, 55), 8);
+        assert_eq!(quadtree.occupancy_histogram(1).iter().sum::<usize>(), quadtree.stats().leaves);
+    }
+
+    #[test]
+    fn loose_aggregates_count_each_point_once() {
+        let points: Vec<Point> = clustered(500, 56)
+            .into_iter()
+            .map(|mut p| {
+                p.radius = if p.id % 10 == 0 { 40.0 } else { 4.0 };
+                p
+            })
+            .collect();
+        let loose = QuadTree::from_points_loose(points.clone(), 0.0, 0.0, 1000.0, 1000.0, 4);
+        let exact = tree(&points, 4);
+        assert!(loose.stats().points > points.len(), "no point was stored twice");
+        assert_eq!(sorted_ids(loose.iter()), sorted_ids(exact.iter()));
+        assert_eq!(loose.positions().len(), points.len());
+        assert!(loose.centroid().unwrap().abs_diff_eq(exact.centroid().unwrap(), 1e-2));
+        assert_eq!(loose.to_uniform_grid(50.0).ids.len(), points.len());
+        for (x, y, radius) in [(150.0, 200.0, 80.0), (500.0, 500.0, 600.0)] {
+            assert_eq!(loose.count_radius(x, y, radius), exact.count_radius(x, y, radius));
+            assert_eq!(loose.radial_profile(x, y, 20.0, 10), exact.radial_profile(x, y, 20.0, 10));
+            let with_distance = |tree: &QuadTree| {
+                sorted_ids(tree.query_radius_with_distance(x, y, radius).into_iter().map(|(p, _)| p))
+            };
+            assert_eq!(with_distance(&loose), with_distance(&exact));
+            let large = sorted_ids(loose.query_radius_filter(x, y, radius, |p| p.radius > 10.0));
+            assert!(!large.is_empty() && large.windows(2).all(|pair| pair[0] != pair[1]));
+            assert_eq!(
+                sorted_ids(loose.query_outside_radius(x, y, radius)),
+                sorted_ids(exact.query_outside_radius(x, y, radius))
+            );
+        }
+    }
+}
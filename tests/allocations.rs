@@ -0,0 +1,76 @@
+//! Allocation counts of tree builds. Kept in its own test binary because the counting allocator
+//! replaces the global allocator of the whole binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use nannou::glam::Vec2;
+use quad_tree::quadtree::{Point, QuadTree};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Counts reallocations and the largest allocation made by the current thread, so tests
+/// running in parallel don't disturb each other.
+struct CountingAllocator;
+
+thread_local! {
+    static REALLOCS: Cell<usize> = const { Cell::new(0) };
+    static LARGEST: Cell<usize> = const { Cell::new(0) };
+}
+
+fn note_size(size: usize) {
+    let _ = LARGEST.try_with(|largest| largest.set(largest.get().max(size)));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        note_size(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = REALLOCS.try_with(|reallocs| reallocs.set(reallocs.get() + 1));
+        note_size(new_size);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Reallocations and largest allocation while running `f` on this thread.
+fn allocations<T>(f: impl FnOnce() -> T) -> (usize, usize) {
+    REALLOCS.with(|reallocs| reallocs.set(0));
+    LARGEST.with(|largest| largest.set(0));
+    std::hint::black_box(f());
+    (REALLOCS.with(|reallocs| reallocs.get()), LARGEST.with(|largest| largest.get()))
+}
+
+/// `n` points spread uniformly over `[0, 1000)²`.
+fn uniform(n: usize, seed: u64) -> Vec<Point> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n).map(|id| {
+        let position = Vec2::new(rng.gen_range(0.0..1000.0), rng.gen_range(0.0..1000.0));
+        Point::new(id, position, position, Vec2::ZERO, 2.0, Default::default())
+    }).collect()
+}
+
+#[test]
+fn sized_build_reallocates_less() {
+    let points = uniform(50_000, 8);
+    let (grown, _) = allocations(|| {
+        let mut tree = QuadTree::new(0.0, 0.0, 1000.0, 1000.0);
+        for point in &points {
+            tree.insert(*point, 16);
+        }
+        tree
+    });
+    let copy = points.clone();
+    let (sized, largest) = allocations(|| QuadTree::from_points(copy, 0.0, 0.0, 1000.0, 1000.0, 16));
+    assert!(sized * 2 < grown, "{} reallocations sized versus {} grown", sized, grown);
+    // The root reserves room for one split, not for every point.
+    assert!(largest < 1000 * std::mem::size_of::<Point>(), "largest allocation {} bytes", largest);
+}